@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS game_results (
+    id UUID PRIMARY KEY,
+    game_id UUID NOT NULL,
+    -- Team that won, or NULL for a draw
+    winner TEXT,
+    moves JSONB NOT NULL,
+    -- Player names by team
+    players JSONB NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- A game is its ID and when it started, since a board can be reset for a new
+-- game under the same ID, and each one is recorded once
+CREATE UNIQUE INDEX IF NOT EXISTS game_results_game ON game_results (game_id, started_at);
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS quote_revisions (
+    id UUID PRIMARY KEY,
+    quote_id UUID NOT NULL,
+    version INT NOT NULL,
+    author TEXT NOT NULL,
+    quote TEXT NOT NULL,
+    -- What was done to the quote: draft, undo, revert or remove, or migrate
+    -- for quotes that were around before revisions were kept
+    action TEXT NOT NULL,
+    revised_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS quote_revisions_quote_id ON quote_revisions (quote_id, version);
+
+-- Quotes from before there was any history start off with what they are now
+INSERT INTO quote_revisions (id, quote_id, version, author, quote, action, revised_at)
+SELECT gen_random_uuid(), id, version, author, quote, 'migrate', created_at FROM quotes;
@@ -0,0 +1,2 @@
+-- Removed quotes stay in the trash until they're restored or purged
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
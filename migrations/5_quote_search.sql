@@ -0,0 +1,8 @@
+-- Words from the quote count for more than words from the author's name
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS search TSVECTOR
+    GENERATED ALWAYS AS (
+        setweight(to_tsvector('english', quote), 'A') ||
+        setweight(to_tsvector('english', author), 'B')
+    ) STORED;
+
+CREATE INDEX IF NOT EXISTS quotes_search ON quotes USING GIN (search);
@@ -0,0 +1,2 @@
+-- Pages of the list are found by where they are in this order
+CREATE INDEX IF NOT EXISTS quotes_created_at_id ON quotes (created_at, id);
@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS tags (
+    name TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS quote_tags (
+    quote_id UUID NOT NULL REFERENCES quotes (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL REFERENCES tags (name) ON DELETE CASCADE,
+    PRIMARY KEY (quote_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS quote_tags_tag ON quote_tags (tag);
+
+CREATE TABLE IF NOT EXISTS collections (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    description TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS collection_quotes (
+    collection_id UUID NOT NULL REFERENCES collections (id) ON DELETE CASCADE,
+    quote_id UUID NOT NULL REFERENCES quotes (id) ON DELETE CASCADE,
+    -- Where the quote comes in the collection, counting from 1
+    position INT NOT NULL,
+    PRIMARY KEY (collection_id, quote_id)
+);
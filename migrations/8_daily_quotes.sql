@@ -0,0 +1,9 @@
+-- The quote of the day, once it's been picked, so that it stays the same all
+-- day however the quote book changes
+CREATE TABLE IF NOT EXISTS daily_quotes (
+    date DATE NOT NULL,
+    -- Name of the time zone whose day it is
+    timezone TEXT NOT NULL,
+    quote_id UUID NOT NULL REFERENCES quotes (id) ON DELETE CASCADE,
+    PRIMARY KEY (date, timezone)
+);
@@ -1,30 +1,120 @@
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use leaky_bucket::RateLimiter;
+use actix_web::web::Data;
+use tokio::sync::watch;
 
-fn build_rate_limiter() -> RateLimiter {
-    RateLimiter::builder()
-        .max(5)
-        .initial(5)
-        .interval(Duration::from_secs(1))
-        .build()
-}
+const CAPACITY: usize = 5;
+/// How long it takes for one unit of milk to come back
+const REGENERATION: Duration = Duration::from_secs(1);
 
 pub struct Bucket {
-    rate_limiter: RateLimiter,
+    milk: usize,
+    /// When milk last came back, or when the bucket was last seen full.
+    /// Regeneration is counted from here.
+    regenerated_at: Instant,
+    level: watch::Sender<usize>,
 }
 
 impl Bucket {
     pub fn new() -> Self {
-        let rate_limiter = build_rate_limiter();
-        Self { rate_limiter }
+        let (level, _) = watch::channel(CAPACITY);
+        Self {
+            milk: CAPACITY,
+            regenerated_at: Instant::now(),
+            level,
+        }
     }
 
-    pub fn get_milk(&self) -> bool {
-        self.rate_limiter.try_acquire(1)
+    pub fn get_milk(&mut self) -> bool {
+        let withdrawn = self.withdraw(Instant::now());
+        self.publish_level();
+        withdrawn
     }
 
     pub fn refill(&mut self) {
-        self.rate_limiter = build_rate_limiter();
+        self.milk = CAPACITY;
+        self.regenerated_at = Instant::now();
+        self.publish_level();
+    }
+
+    /// Subscribe to changes in the amount of milk left in the bucket
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.level.subscribe()
+    }
+
+    fn withdraw(&mut self, now: Instant) -> bool {
+        self.regenerate(now);
+        if self.milk == 0 {
+            return false;
+        }
+        self.milk -= 1;
+        true
+    }
+
+    /// Add the milk that has come back since it was last counted
+    fn regenerate(&mut self, now: Instant) {
+        while self.milk < CAPACITY && now >= self.regenerated_at + REGENERATION {
+            self.milk += 1;
+            self.regenerated_at += REGENERATION;
+        }
+        // A full bucket doesn't save up regeneration for later
+        if self.milk == CAPACITY {
+            self.regenerated_at = now;
+        }
+    }
+
+    /// Notify subscribers if the level has changed
+    fn publish_level(&mut self) {
+        self.regenerate(Instant::now());
+        let level = self.milk;
+        self.level.send_if_modified(|current| {
+            if *current == level {
+                false
+            } else {
+                *current = level;
+                true
+            }
+        });
+    }
+}
+
+/// Periodically check the bucket, so that subscribers hear about milk that
+/// regenerated on its own
+pub fn watch_regeneration(bucket: Data<Mutex<Bucket>>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REGENERATION);
+        loop {
+            interval.tick().await;
+            bucket.lock().unwrap().publish_level();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn milk_comes_back_over_time() {
+        let mut bucket = Bucket::new();
+        let start = bucket.regenerated_at;
+
+        for _ in 0..CAPACITY {
+            assert!(bucket.withdraw(start));
+        }
+        assert!(!bucket.withdraw(start));
+
+        // Two units are back after two and a half seconds, and the half second
+        // counts towards the next one
+        let later = start + REGENERATION * 5 / 2;
+        assert!(bucket.withdraw(later));
+        assert!(bucket.withdraw(later));
+        assert!(!bucket.withdraw(later));
+        assert!(bucket.withdraw(start + REGENERATION * 3));
+
+        // Nothing comes back beyond what the bucket holds
+        bucket.regenerate(start + REGENERATION * 100);
+        assert_eq!(CAPACITY, bucket.milk);
     }
 }
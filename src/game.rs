@@ -1,13 +1,36 @@
 #![allow(clippy::module_name_repetitions)]
 
+use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
 
-use actix_web::web::{Data, Path};
-use actix_web::{get, post, Either, HttpResponse, Scope};
+use actix_web::http::header::{self, ContentType, Header as _, HeaderName};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Json, Path, Payload, Query};
+use actix_web::{get, post, HttpRequest, HttpResponse, Scope};
+use actix_ws::Message;
+use chrono::{DateTime, Utc};
+use jwt_simple::prelude::{Claims, HS256Key, MACLike};
 use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
-use serde::Deserialize;
-use tokio::sync::{Mutex, RwLock};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use uuid::Uuid;
+
+mod opponent;
+mod position;
+mod stats;
+mod team;
+mod tournament;
+
+use opponent::Difficulty;
+use stats::{GameResult, SharedDBPool};
+pub use team::new_shared_teams;
+use team::{Roster, SharedTeams};
+use tournament::SharedTournaments;
+pub use tournament::{expire_idle_tournaments, new_shared_tournaments};
 
 pub type SharedRng = Data<Mutex<StdRng>>;
 
@@ -19,25 +42,73 @@ pub fn new_shared_rng() -> SharedRng {
     Data::new(Mutex::new(new_seeded_rng()))
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
-#[serde(untagged, rename_all = "kebab-case")]
-enum Piece {
-    Cookie,
-    Milk,
+/// A piece played by one of the teams in a game's roster. Clients only ever
+/// see the team's name; the number is for tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Piece(usize);
+
+/// Dimensions of the board, the number of pieces in a row needed to win and
+/// how many teams are playing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BoardConfig {
+    width: usize,
+    height: usize,
+    connect: usize,
+    teams: usize,
 }
 
-impl Display for Piece {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
-        match self {
-            Self::Cookie => write!(f, "🍪"),
-            Self::Milk => write!(f, "🥛"),
+impl BoardConfig {
+    const MAX_SIZE: usize = 16;
+
+    /// Whether a game could be played like this by teams from the roster
+    fn is_valid(&self, roster: &Roster) -> bool {
+        (1..=Self::MAX_SIZE).contains(&self.width)
+            && (1..=Self::MAX_SIZE).contains(&self.height)
+            && (2..=self.width.max(self.height)).contains(&self.connect)
+            && (2..=roster.len()).contains(&self.teams)
+    }
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self {
+            width: 4,
+            height: 4,
+            connect: 4,
+            teams: 2,
         }
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// A piece placed by a player, in the order it was played
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Move {
+    team: Piece,
+    /// Column as numbered in the place route, starting at 1
+    column: usize,
+}
+
+/// A move as clients see it, with the team by name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct MoveView {
+    team: String,
+    column: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Game {
-    board: [[Option<Piece>; 4]; 4],
+    config: BoardConfig,
+    /// The teams that pieces on the board belong to
+    roster: Roster,
+    /// Columns of the board, top cell first
+    board: Vec<Vec<Option<Piece>>>,
+    moves: Vec<Move>,
+    /// The board the moves were played on, if the game was loaded part way
+    /// through rather than started from an empty board
+    start: Option<Vec<Vec<Option<Piece>>>>,
+    /// The team that played the first of the moves
+    first_turn: Piece,
 }
 
 enum GameState {
@@ -46,27 +117,83 @@ enum GameState {
     NotEnded,
 }
 
+/// Directions a line can run in, as (column, row) steps. Rows count from the
+/// top, so the last one runs from bottom-left to top-right.
+const DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+
 impl Game {
     fn new() -> Self {
-        let board = Default::default();
-        Self { board }
+        Self::with_config(BoardConfig::default(), Roster::default())
     }
 
-    fn random(rng: &mut StdRng) -> Self {
-        let mut game = Self::new();
-        for i in 0..4 {
-            for j in 0..4 {
-                let piece = if rng.gen() {
-                    Piece::Cookie
-                } else {
-                    Piece::Milk
-                };
-                game.board[j][i] = Some(piece);
+    fn with_config(config: BoardConfig, roster: Roster) -> Self {
+        let board = vec![vec![None; config.height]; config.width];
+        Self {
+            config,
+            roster,
+            board,
+            moves: Vec::new(),
+            start: None,
+            first_turn: Piece::COOKIE,
+        }
+    }
+
+    /// Carry on from the pieces on the board as if they'd been there from the
+    /// start, with `first_turn` to play next
+    fn set_start(&mut self, first_turn: Piece) {
+        self.moves.clear();
+        self.start = Some(self.board.clone());
+        self.first_turn = first_turn;
+    }
+
+    /// The game as it was before any moves were played
+    fn restarted(&self) -> Self {
+        let mut game = Self::with_config(self.config, self.roster.clone());
+        if let Some(start) = &self.start {
+            game.board.clone_from(start);
+            game.start = Some(start.clone());
+        }
+        game.first_turn = self.first_turn;
+        game
+    }
+
+    /// Fill the whole board with random pieces
+    fn random(config: BoardConfig, roster: Roster, rng: &mut StdRng) -> Self {
+        let mut game = Self::with_config(config, roster);
+        for i in 0..game.config.height {
+            for j in 0..game.config.width {
+                game.board[j][i] = Some(Piece::random(config.teams, rng));
+            }
+        }
+        game
+    }
+
+    /// Play random moves, with the teams taking turns, until either a
+    /// randomly chosen number of moves have been played or the game is over
+    fn random_reachable(config: BoardConfig, roster: Roster, rng: &mut StdRng) -> Self {
+        let mut game = Self::with_config(config, roster);
+        let moves = rng.gen_range(0..=config.width * config.height);
+        let mut piece = Piece::random(config.teams, rng);
+
+        for _ in 0..moves {
+            let Some(&column) = game.open_columns().choose(rng) else {
+                break;
+            };
+            if !game.place(piece, column) {
+                break;
             }
+            piece = piece.next(config.teams);
         }
         game
     }
 
+    /// Columns (starting at 0) with space left in them
+    fn open_columns(&self) -> Vec<usize> {
+        (0..self.config.width)
+            .filter(|&column| self.board[column][0].is_none())
+            .collect()
+    }
+
     fn place(&mut self, piece: Piece, column: usize) -> bool {
         if !matches!(self.get_state(), GameState::NotEnded) {
             return false;
@@ -78,68 +205,125 @@ impl Game {
             .last()
         {
             *last = Some(piece);
+            self.moves.push(Move {
+                team: piece,
+                column: column + 1,
+            });
             true
         } else {
             false
         }
     }
 
-    fn reset(&mut self) {
-        self.board = Default::default();
+    /// Take back the last move, if there was one
+    fn undo(&mut self) -> Option<Move> {
+        let last = self.moves.pop()?;
+        if let Some(top) = self.board[last.column - 1].iter_mut().find(|o| o.is_some()) {
+            *top = None;
+        }
+        Some(last)
     }
 
-    /// Get game state
-    fn get_state(&self) -> GameState {
-        // Check columns
-        for i in 0..4 {
-            let mut winner = self.board[i][0];
-            for j in 1..4 {
-                if self.board[i][j] != winner {
-                    winner = None;
-                }
-            }
-            if let Some(w) = winner {
-                return GameState::Winner(w);
-            }
+    /// Rebuild the board as it was after the first `moves` moves
+    fn replay(&self, moves: usize) -> Option<Self> {
+        let mut game = self.restarted();
+        for m in self.moves.get(..moves)? {
+            game.place(m.team, m.column - 1);
         }
+        Some(game)
+    }
 
-        // Check rows
-        for i in 0..4 {
-            let mut winner = self.board[0][i];
-            for j in 1..4 {
-                if self.board[j][i] != winner {
-                    winner = None;
-                }
-            }
-            if let Some(w) = winner {
-                return GameState::Winner(w);
-            }
+    fn view_move(&self, m: Move) -> MoveView {
+        MoveView {
+            team: self.roster.name(m.team).to_string(),
+            column: m.column,
         }
+    }
 
-        // Check top-left to bottom-right diagonal
-        {
-            let mut winner = self.board[0][0];
-            for i in 1..4 {
-                if self.board[i][i] != winner {
-                    winner = None;
-                }
-            }
-            if let Some(w) = winner {
-                return GameState::Winner(w);
+    /// The team whose piece was placed last
+    fn last_turn(&self) -> Option<Piece> {
+        self.moves.last().map(|m| m.team)
+    }
+
+    /// Whether the given team is one of the teams playing
+    fn has_team(&self, piece: Piece) -> bool {
+        piece.0 < self.config.teams
+    }
+
+    /// Whether it's the given team's turn
+    fn is_turn(&self, piece: Piece) -> bool {
+        self.last_turn()
+            .map_or(self.first_turn, |last| last.next(self.config.teams))
+            == piece
+    }
+
+    /// The position the moves were played from, in the compact notation, if
+    /// the game was loaded
+    fn start_position(&self) -> Option<String> {
+        self.start
+            .as_ref()
+            .map(|_| position::compact(&self.restarted()))
+    }
+
+    /// Write out the moves played so far, in a notation loosely based on PGN
+    fn notation(&self) -> String {
+        let result = match self.get_state() {
+            GameState::Winner(w) => self.roster.emoji(w).to_string(),
+            GameState::Draw => "draw".to_string(),
+            GameState::NotEnded => "*".to_string(),
+        };
+
+        let moves = self
+            .moves
+            .iter()
+            .enumerate()
+            .map(|(i, m)| format!("{}. {}{}", i + 1, self.roster.emoji(m.team), m.column))
+            .chain(std::iter::once(result.clone()))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        // Two teams is the norm, so only worth a tag when there are more
+        let teams = if self.config.teams == BoardConfig::default().teams {
+            String::new()
+        } else {
+            format!("[Teams \"{}\"]\n", self.config.teams)
+        };
+        // Like FEN in PGN, for games that didn't start from an empty board
+        let start = self
+            .start_position()
+            .map(|position| format!("[Position \"{position}\"]\n"))
+            .unwrap_or_default();
+        format!(
+            "[Width \"{}\"]\n[Height \"{}\"]\n[Connect \"{}\"]\n{teams}{start}[Result \"{result}\"]\n\n{moves}\n",
+            self.config.width, self.config.height, self.config.connect
+        )
+    }
+
+    /// Get the piece that has `connect` in a row starting from the given cell
+    fn line_from(&self, column: usize, row: usize, (dc, dr): (isize, isize)) -> Option<Piece> {
+        let piece = self.board[column][row]?;
+        let (mut column, mut row) = (column, row);
+        for _ in 1..self.config.connect {
+            column = column.checked_add_signed(dc)?;
+            row = row.checked_add_signed(dr)?;
+            if *self.board.get(column)?.get(row)? != Some(piece) {
+                return None;
             }
         }
+        Some(piece)
+    }
 
-        // Check top-right to bottom-left
-        {
-            let mut winner = self.board[3][0];
-            for (i, j) in [(2, 1), (1, 2), (0, 3)] {
-                if self.board[i][j] != winner {
-                    winner = None;
+    /// Get game state
+    fn get_state(&self) -> GameState {
+        // Check columns, then rows, then both diagonals
+        for direction in DIRECTIONS {
+            for column in 0..self.config.width {
+                for row in 0..self.config.height {
+                    if let Some(w) = self.line_from(column, row, direction) {
+                        return GameState::Winner(w);
+                    }
                 }
             }
-            if let Some(w) = winner {
-                return GameState::Winner(w);
-            }
         }
 
         // If board is full, it's a draw, otherwise ongoing
@@ -151,17 +335,17 @@ impl Game {
     }
 }
 
+const WALL: char = '⬜';
+const EMPTY: char = '⬛';
+
 impl Display for Game {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
-        const WALL: char = '⬜';
-        const EMPTY: char = '⬛';
-
         // Main board
-        for i in 0..4 {
+        for i in 0..self.config.height {
             write!(f, "{WALL}")?;
-            for j in 0..4 {
+            for j in 0..self.config.width {
                 if let Some(piece) = self.board[j][i] {
-                    write!(f, "{piece}")?;
+                    write!(f, "{}", self.roster.emoji(piece))?;
                 } else {
                     write!(f, "{EMPTY}")?;
                 }
@@ -171,7 +355,7 @@ impl Display for Game {
         }
 
         // Bottom
-        for _ in 0..6 {
+        for _ in 0..self.config.width + 2 {
             write!(f, "{WALL}")?;
         }
         writeln!(f)?;
@@ -179,7 +363,7 @@ impl Display for Game {
         // Winner state
         match self.get_state() {
             GameState::Winner(w) => {
-                writeln!(f, "{w} wins!")?;
+                writeln!(f, "{} wins!", self.roster.emoji(w))?;
             }
             GameState::Draw => {
                 writeln!(f, "No winner.")?;
@@ -191,68 +375,1230 @@ impl Display for Game {
     }
 }
 
-pub type SharedGame = Data<RwLock<Game>>;
+/// The game behind the `/12/board` family of routes
+const DEFAULT_GAME: Uuid = Uuid::nil();
+
+/// How long a game can go without a move before it is removed
+const MAX_IDLE: Duration = Duration::from_hours(1);
+
+/// How long a player keeps their seat before they have to join again
+const SEAT_LIFETIME: Duration = Duration::from_hours(12);
+
+/// Something that happened to a game, as sent to anyone watching it
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum GameEvent {
+    Move(MoveView),
+    Undo(MoveView),
+    Reset(BoardConfig),
+    Load,
+    RandomBoard,
+}
+
+#[derive(Debug, Clone)]
+struct Update {
+    board: String,
+    event: GameEvent,
+}
+
+struct Session {
+    game: Game,
+    last_active: Instant,
+    /// Players that have joined the game, by team
+    seats: HashMap<Piece, SeatHolder>,
+    /// Whether anyone has ever joined the game. From then on moves need a
+    /// seat token and teams have to take turns, even once every seat has
+    /// been given up or run out.
+    closed: bool,
+    /// Whether the game was set up for a tournament match, which hands out
+    /// the seats itself. Nobody can join it or put another game on the board.
+    locked: bool,
+    /// How long a seat lasts before whoever has it has to take it again
+    seat_lifetime: Duration,
+    updates: broadcast::Sender<Update>,
+    /// When the current game on the board started
+    started_at: DateTime<Utc>,
+    /// Whether the game gets removed once it's been idle for a while
+    expires: bool,
+}
+
+impl Session {
+    fn new(game: Game, expires: bool) -> Self {
+        let (updates, _) = broadcast::channel(16);
+        Self {
+            game,
+            last_active: Instant::now(),
+            seats: HashMap::new(),
+            closed: false,
+            locked: false,
+            seat_lifetime: SEAT_LIFETIME,
+            updates,
+            started_at: Utc::now(),
+            expires,
+        }
+    }
+
+    /// Start over with a different game on the board
+    fn replace(&mut self, game: Game) {
+        self.game = game;
+        self.started_at = Utc::now();
+        // Whoever played the last game has to join this one to play it. It
+        // stays closed to anyone who hasn't.
+        self.seats.clear();
+    }
+
+    /// Give `player` the seat for `team`, and the token to play from it with
+    fn seat(&mut self, id: &Uuid, team: Piece, player: String, key: &SeatKey) -> String {
+        let seat = Seat {
+            game: *id,
+            team,
+            player: player.clone(),
+            id: Uuid::new_v4(),
+        };
+        self.closed = true;
+        self.seats.insert(
+            team,
+            SeatHolder {
+                player,
+                seat: seat.id,
+                until: Instant::now() + self.seat_lifetime,
+            },
+        );
+        key.sign(seat, self.seat_lifetime)
+    }
+
+    /// Whether the seat in a token is still the one at the table
+    fn holds_seat(&self, id: &Uuid, seat: &Seat) -> bool {
+        seat.game == *id
+            && self
+                .seats
+                .get(&seat.team)
+                .is_some_and(|holder| holder.seat == seat.id)
+    }
+
+    /// Free up the seats whose tokens have run out by `now`
+    fn release_expired_seats(&mut self, now: Instant) {
+        self.seats.retain(|_, holder| holder.until > now);
+    }
+
+    /// The outcome of the game, if it's over. Games carried on from a loaded
+    /// position don't have all their moves, so they don't count.
+    fn result(&self, id: &Uuid) -> Option<GameResult> {
+        if self.game.start.is_some() {
+            return None;
+        }
+        let roster = &self.game.roster;
+        let winner = match self.game.get_state() {
+            GameState::Winner(w) => Some(roster.name(w).to_string()),
+            GameState::Draw => None,
+            GameState::NotEnded => return None,
+        };
+        Some(GameResult {
+            game: *id,
+            winner,
+            moves: self
+                .game
+                .moves
+                .iter()
+                .map(|&m| self.game.view_move(m))
+                .collect(),
+            players: self
+                .seats
+                .iter()
+                .map(|(&team, holder)| (roster.name(team).to_string(), holder.player.clone()))
+                .collect(),
+            started_at: self.started_at,
+        })
+    }
+
+    /// Let anyone watching know about a change to the game
+    fn publish(&self, event: GameEvent) {
+        self.publish_board(self.game.to_string(), event);
+    }
+
+    /// Let anyone watching know about a board that isn't necessarily the one
+    /// being played
+    fn publish_board(&self, board: String, event: GameEvent) {
+        // Nobody might be watching, which is fine
+        let _ = self.updates.send(Update { board, event });
+    }
+}
+
+/// Claims of the token handed out to a player when they join a game
+#[derive(Debug, Serialize, Deserialize)]
+struct Seat {
+    game: Uuid,
+    team: Piece,
+    player: String,
+    /// Tells this seat apart from anyone else's who sat there before
+    id: Uuid,
+}
+
+/// Whoever is sitting at a game for a team
+#[derive(Debug)]
+struct SeatHolder {
+    player: String,
+    seat: Uuid,
+    /// When the seat's token runs out
+    until: Instant,
+}
+
+/// The key seat tokens are signed with, along with the tokens tournament
+/// players get their seats with. Nothing else is signed with it, so no other
+/// token the server hands out can be passed off as one of these.
+pub struct SeatKey(HS256Key);
+
+pub type SharedSeatKey = Data<SeatKey>;
+
+/// Games don't outlive the server, so their seats don't need a lasting key
+pub fn new_seat_key() -> SharedSeatKey {
+    Data::new(SeatKey(HS256Key::generate()))
+}
+
+impl SeatKey {
+    fn sign<T: Serialize + DeserializeOwned>(&self, claims: T, lifetime: Duration) -> String {
+        let lifetime = jwt_simple::prelude::Duration::from_secs(lifetime.as_secs());
+        self.0
+            .authenticate(Claims::with_custom_claims(claims, lifetime))
+            .expect("key should be valid")
+    }
+
+    fn verify<T: Serialize + DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let claims = self.0.verify_token::<T>(token, None).ok()?;
+        Some(claims.custom)
+    }
+}
+
+/// All games currently being played, by ID
+pub struct Games {
+    sessions: HashMap<Uuid, Session>,
+}
+
+impl Games {
+    fn new() -> Self {
+        let sessions = HashMap::from([(DEFAULT_GAME, Session::new(Game::new(), false))]);
+        Self { sessions }
+    }
+
+    fn create(&mut self, game: Game) -> Uuid {
+        self.insert(Session::new(game, true))
+    }
 
-pub fn new_shared_game() -> SharedGame {
-    Data::new(RwLock::new(Game::new()))
+    /// Create a game that sticks around however long it sits idle, until it's
+    /// released
+    fn create_kept(&mut self, game: Game) -> Uuid {
+        self.insert(Session::new(game, false))
+    }
+
+    /// Create a kept game whose seats are handed out by the caller, and last
+    /// `seat_lifetime`. Nobody can join it, reset it or load another game
+    /// onto it.
+    fn create_locked(&mut self, game: Game, seat_lifetime: Duration) -> Uuid {
+        let id = self.create_kept(game);
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .expect("the game was just created");
+        session.locked = true;
+        session.seat_lifetime = seat_lifetime;
+        id
+    }
+
+    /// Let a kept game be removed once it's been idle for a while, like any
+    /// other
+    fn release(&mut self, id: &Uuid) {
+        if let Some(session) = self.sessions.get_mut(id) {
+            session.expires = true;
+        }
+    }
+
+    fn insert(&mut self, session: Session) -> Uuid {
+        let id = Uuid::new_v4();
+        self.sessions.insert(id, session);
+        id
+    }
+
+    fn get(&self, id: &Uuid) -> Option<&Game> {
+        self.get_session(id).map(|session| &session.game)
+    }
+
+    fn get_session(&self, id: &Uuid) -> Option<&Session> {
+        self.sessions.get(id)
+    }
+
+    /// Get a game to make changes to it, which counts as activity
+    fn get_mut(&mut self, id: &Uuid) -> Option<&mut Session> {
+        let session = self.sessions.get_mut(id)?;
+        session.last_active = Instant::now();
+        session.release_expired_seats(session.last_active);
+        Some(session)
+    }
+
+    /// Remove the games that have gone without a move for too long as of `now`
+    fn expire_idle(&mut self, now: Instant) {
+        self.sessions.retain(|_, session| {
+            !session.expires || now.saturating_duration_since(session.last_active) < MAX_IDLE
+        });
+    }
+}
+
+pub type SharedGames = Data<RwLock<Games>>;
+
+pub fn new_shared_games() -> SharedGames {
+    Data::new(RwLock::new(Games::new()))
+}
+
+/// Periodically remove games that nobody has played in a while
+pub fn expire_idle_games(games: SharedGames) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_mins(1));
+        loop {
+            interval.tick().await;
+            games.write().await.expire_idle(Instant::now());
+        }
+    });
+}
+
+/// How a board gets sent back to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    /// Pick the format the client prefers, going with `default` if they
+    /// don't mind
+    fn negotiate(request: &HttpRequest, default: Self) -> Self {
+        let Ok(accept) = header::Accept::parse(request) else {
+            return default;
+        };
+        match accept.preference().essence_str() {
+            "application/json" => Self::Json,
+            "text/plain" => Self::Text,
+            _ => default,
+        }
+    }
+}
+
+/// The board as sent to clients that ask for JSON
+#[derive(Debug, Serialize)]
+struct BoardView {
+    #[serde(flatten)]
+    config: BoardConfig,
+    /// Rows of the board, top row first, with the team each piece is from
+    cells: Vec<Vec<Option<String>>>,
+    state: &'static str,
+    winner: Option<String>,
+    /// How many pieces are in each column, which is also the height the next
+    /// piece in that column lands at, counting from 0 at the bottom
+    heights: Vec<usize>,
+}
+
+impl From<&Game> for BoardView {
+    fn from(game: &Game) -> Self {
+        let name = |piece| game.roster.name(piece).to_string();
+        let cells = (0..game.config.height)
+            .map(|row| {
+                game.board
+                    .iter()
+                    .map(|column| column[row].map(name))
+                    .collect()
+            })
+            .collect();
+        let heights = game
+            .board
+            .iter()
+            .map(|column| column.iter().filter(|o| o.is_some()).count())
+            .collect();
+        let (state, winner) = match game.get_state() {
+            GameState::Winner(w) => ("won", Some(name(w))),
+            GameState::Draw => ("draw", None),
+            GameState::NotEnded => ("ongoing", None),
+        };
+
+        Self {
+            config: game.config,
+            cells,
+            state,
+            winner,
+            heights,
+        }
+    }
+}
+
+fn render(game: &Game, format: Format, status: StatusCode) -> HttpResponse {
+    let mut response = HttpResponse::build(status);
+    match format {
+        Format::Text => response
+            .content_type(ContentType::plaintext())
+            .body(game.to_string()),
+        Format::Json => response.json(BoardView::from(game)),
+    }
+}
+
+async fn show(games: &SharedGames, id: &Uuid, format: Format) -> HttpResponse {
+    match games.read().await.get(id) {
+        Some(game) => render(game, format, StatusCode::OK),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn reset(
+    games: &SharedGames,
+    teams: &SharedTeams,
+    id: &Uuid,
+    config: BoardConfig,
+    key: &SeatKey,
+    token: Option<&str>,
+    format: Format,
+) -> HttpResponse {
+    let roster = teams.read().await.clone();
+    if !config.is_valid(&roster) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut games = games.write().await;
+    let Some(session) = games.get_mut(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    if let Err(response) = check_restart(session, id, key, token) {
+        return response;
+    }
+    session.replace(Game::with_config(config, roster));
+    session.publish(GameEvent::Reset(config));
+    render(&session.game, format, StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaceParams {
+    team: String,
+    column: String,
+}
+
+/// Get the bearer token sent with a request, if any
+pub(crate) fn bearer_token(request: &HttpRequest) -> Option<&str> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Check that the token is for a seat at the given game, for any team, and
+/// that the seat is still theirs
+fn check_seated(
+    session: &Session,
+    id: &Uuid,
+    key: &SeatKey,
+    token: Option<&str>,
+) -> Result<Seat, HttpResponse> {
+    let Some(seat) = token.and_then(|t| key.verify::<Seat>(t)) else {
+        return Err(HttpResponse::Unauthorized().finish());
+    };
+    if !session.holds_seat(id, &seat) {
+        return Err(HttpResponse::Forbidden().finish());
+    }
+    Ok(seat)
+}
+
+/// Check that the token is for the seat at the given game and team, and that
+/// the seat is still theirs
+fn check_seat(
+    session: &Session,
+    id: &Uuid,
+    team: Piece,
+    key: &SeatKey,
+    token: Option<&str>,
+) -> Result<(), HttpResponse> {
+    let seat = check_seated(session, id, key, token)?;
+    if seat.team != team {
+        return Err(HttpResponse::Forbidden().finish());
+    }
+    Ok(())
+}
+
+/// Check that whoever sent `token` can put a different game on the board.
+/// Anyone can until someone joins; after that, only someone sitting at it.
+/// Nobody can restart a locked game.
+fn check_restart(
+    session: &Session,
+    id: &Uuid,
+    key: &SeatKey,
+    token: Option<&str>,
+) -> Result<(), HttpResponse> {
+    if session.locked {
+        return Err(HttpResponse::Forbidden().finish());
+    }
+    if session.closed {
+        check_seated(session, id, key, token)?;
+    }
+    Ok(())
+}
+
+/// Check that `piece` can be played now. Anyone can play until someone joins
+/// the game; after that it takes the seat's token, and teams take turns.
+fn check_turn(
+    session: &Session,
+    id: &Uuid,
+    piece: Piece,
+    key: &SeatKey,
+    token: Option<&str>,
+    opponent: bool,
+    format: Format,
+) -> Result<(), HttpResponse> {
+    if !session.closed {
+        return Ok(());
+    }
+    check_seat(session, id, piece, key, token)?;
+    if !session.game.is_turn(piece) {
+        return Err(render(&session.game, format, StatusCode::CONFLICT));
+    }
+    // The server can't play for someone who has joined
+    if opponent
+        && session
+            .seats
+            .contains_key(&piece.next(session.game.config.teams))
+    {
+        return Err(render(&session.game, format, StatusCode::CONFLICT));
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn place(
+    games: &SharedGames,
+    id: &Uuid,
+    params: &PlaceParams,
+    key: &SeatKey,
+    token: Option<&str>,
+    opponent: Option<(Difficulty, &SharedRng)>,
+    pool: &SharedDBPool,
+    format: Format,
+) -> HttpResponse {
+    if let Ok(column) = params.column.parse::<usize>() {
+        let mut sessions = games.write().await;
+        let Some(session) = sessions.get_mut(id) else {
+            return HttpResponse::NotFound().finish();
+        };
+        // Teams are looked up in the game's own roster, since the registry
+        // may have changed since it started
+        let Some(piece) = session.game.roster.find_team(&params.team) else {
+            return HttpResponse::BadRequest().finish();
+        };
+        if !session.game.has_team(piece) {
+            return HttpResponse::BadRequest().finish();
+        }
+
+        if let Err(response) =
+            check_turn(session, id, piece, key, token, opponent.is_some(), format)
+        {
+            return response;
+        }
+
+        if (1..=session.game.config.width).contains(&column) {
+            eprintln!("GAME STATE");
+            eprintln!("{}", session.game);
+            eprintln!("Trying to add {} to column {column}", params.team);
+
+            if session.game.place(piece, column - 1) {
+                let placed = session.game.view_move(Move {
+                    team: piece,
+                    column,
+                });
+                session.publish(GameEvent::Move(placed));
+
+                let ongoing = matches!(session.game.get_state(), GameState::NotEnded);
+                if let Some((difficulty, rng)) = opponent.filter(|_| ongoing) {
+                    let game = session.game.clone();
+                    let team = piece.next(game.config.teams);
+                    drop(sessions);
+                    return play_reply(games, id, game, team, difficulty, rng, pool, format).await;
+                }
+
+                if let Some(result) = session.result(id) {
+                    result.record(pool.clone());
+                }
+                return render(&session.game, format, StatusCode::OK);
+            }
+
+            return render(&session.game, format, StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+
+    HttpResponse::BadRequest().finish()
+}
+
+/// Have the server play for `team`, in reply to the move that left the board
+/// as it is in `game`
+#[allow(clippy::too_many_arguments)]
+async fn play_reply(
+    games: &SharedGames,
+    id: &Uuid,
+    game: Game,
+    team: Piece,
+    difficulty: Difficulty,
+    rng: &SharedRng,
+    pool: &SharedDBPool,
+    format: Format,
+) -> HttpResponse {
+    // Looking ahead can take a while, so it's done on a copy of the game, on a
+    // thread where it doesn't hold up anything else
+    let seed = rng.lock().await.gen();
+    let searched = game.clone();
+    let reply = tokio::task::spawn_blocking(move || {
+        let mut rng = StdRng::seed_from_u64(seed);
+        opponent::choose_move(&searched, team, difficulty, &mut rng)
+    })
+    .await
+    .expect("the opponent shouldn't panic");
+
+    let mut sessions = games.write().await;
+    let Some(session) = sessions.get_mut(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    // The reply only makes sense for the board it was worked out for
+    if session.game != game {
+        return render(&session.game, format, StatusCode::CONFLICT);
+    }
+    if let Some(column) = reply {
+        session.game.place(team, column);
+        let placed = session.game.view_move(Move {
+            team,
+            column: column + 1,
+        });
+        session.publish(GameEvent::Move(placed));
+    }
+
+    if let Some(result) = session.result(id) {
+        result.record(pool.clone());
+    }
+    render(&session.game, format, StatusCode::OK)
+}
+
+async fn undo(
+    games: &SharedGames,
+    id: &Uuid,
+    key: &SeatKey,
+    token: Option<&str>,
+    format: Format,
+) -> HttpResponse {
+    let mut games = games.write().await;
+    let Some(session) = games.get_mut(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let Some(last_turn) = session.game.last_turn() else {
+        if session.game.start.is_some() {
+            return HttpResponse::Conflict()
+                .body("The pieces on the board were loaded, so they can't be taken back");
+        }
+        return render(&session.game, format, StatusCode::BAD_REQUEST);
+    };
+
+    // Only the player who made the move gets to take it back
+    if session.closed {
+        if let Err(response) = check_seat(session, id, last_turn, key, token) {
+            return response;
+        }
+    }
+
+    if let Some(undone) = session.game.undo() {
+        session.publish(GameEvent::Undo(session.game.view_move(undone)));
+    }
+    render(&session.game, format, StatusCode::OK)
+}
+
+#[derive(Debug, Serialize)]
+struct History {
+    #[serde(flatten)]
+    config: BoardConfig,
+    /// The position the moves were played from, if the game was loaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start: Option<String>,
+    moves: Vec<MoveView>,
+}
+
+async fn history(games: &SharedGames, id: &Uuid, request: &HttpRequest) -> HttpResponse {
+    let games = games.read().await;
+    let Some(game) = games.get(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    match Format::negotiate(request, Format::Json) {
+        Format::Text => HttpResponse::Ok().body(game.notation()),
+        Format::Json => HttpResponse::Ok().json(History {
+            config: game.config,
+            start: game.start_position(),
+            moves: game.moves.iter().map(|&m| game.view_move(m)).collect(),
+        }),
+    }
+}
+
+async fn replay(games: &SharedGames, id: &Uuid, moves: usize, format: Format) -> HttpResponse {
+    let games = games.read().await;
+    let Some(game) = games.get(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    match game.replay(moves) {
+        Some(game) => render(&game, format, StatusCode::OK),
+        None => HttpResponse::BadRequest().finish(),
+    }
 }
 
 #[get("/board")]
-async fn show_board(game: SharedGame) -> String {
-    game.read().await.to_string()
+async fn show_board(games: SharedGames, request: HttpRequest) -> HttpResponse {
+    show(
+        &games,
+        &DEFAULT_GAME,
+        Format::negotiate(&request, Format::Text),
+    )
+    .await
 }
 
 #[post("/reset")]
-async fn reset_board(game: SharedGame, rng: SharedRng) -> String {
+async fn reset_board(
+    games: SharedGames,
+    teams: SharedTeams,
+    rng: SharedRng,
+    key: SharedSeatKey,
+    config: Query<BoardConfig>,
+    request: HttpRequest,
+) -> HttpResponse {
     // Reset rng inside block
     {
         let mut rng = rng.lock().await;
         *rng = new_seeded_rng();
     }
 
-    let mut game = game.write().await;
-    game.reset();
-    game.to_string()
+    let format = Format::negotiate(&request, Format::Text);
+    let token = bearer_token(&request);
+    let config = config.into_inner();
+    reset(&games, &teams, &DEFAULT_GAME, config, &key, token, format).await
 }
 
 #[derive(Debug, Deserialize)]
-struct PlaceParams {
+struct OpponentQuery {
+    /// Have the server reply with a move of its own
+    opponent: Option<Difficulty>,
+}
+
+#[post("/place/{team}/{column}")]
+async fn place_piece(
+    params: Path<PlaceParams>,
+    query: Query<OpponentQuery>,
+    games: SharedGames,
+    rng: SharedRng,
+    pool: SharedDBPool,
+    key: SharedSeatKey,
+    request: HttpRequest,
+) -> HttpResponse {
+    let opponent = query.opponent.map(|difficulty| (difficulty, &rng));
+    let token = bearer_token(&request);
+    let format = Format::negotiate(&request, Format::Text);
+    place(
+        &games,
+        &DEFAULT_GAME,
+        &params,
+        &key,
+        token,
+        opponent,
+        &pool,
+        format,
+    )
+    .await
+}
+
+#[post("/undo")]
+async fn undo_move(games: SharedGames, key: SharedSeatKey, request: HttpRequest) -> HttpResponse {
+    let format = Format::negotiate(&request, Format::Text);
+    undo(&games, &DEFAULT_GAME, &key, bearer_token(&request), format).await
+}
+
+#[get("/history")]
+async fn show_history(games: SharedGames, request: HttpRequest) -> HttpResponse {
+    history(&games, &DEFAULT_GAME, &request).await
+}
+
+#[get("/replay/{moves}")]
+async fn replay_board(
+    moves: Path<usize>,
+    games: SharedGames,
+    request: HttpRequest,
+) -> HttpResponse {
+    let format = Format::negotiate(&request, Format::Text);
+    replay(&games, &DEFAULT_GAME, *moves, format).await
+}
+
+#[derive(Debug, Deserialize)]
+struct PositionQuery {
+    connect: Option<usize>,
+}
+
+impl PositionQuery {
+    fn connect(&self) -> usize {
+        self.connect.unwrap_or(BoardConfig::default().connect)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn load(
+    games: &SharedGames,
+    teams: &SharedTeams,
+    id: &Uuid,
+    position: &str,
+    connect: usize,
+    key: &SeatKey,
+    token: Option<&str>,
+    format: Format,
+) -> HttpResponse {
+    let roster = teams.read().await.clone();
+    let loaded = match position::parse(position, connect, roster) {
+        Ok(game) => game,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+
+    let mut games = games.write().await;
+    let Some(session) = games.get_mut(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    if let Err(response) = check_restart(session, id, key, token) {
+        return response;
+    }
+    session.replace(loaded);
+    session.publish(GameEvent::Load);
+    render(&session.game, format, StatusCode::OK)
+}
+
+async fn export(games: &SharedGames, id: &Uuid) -> HttpResponse {
+    match games.read().await.get(id) {
+        Some(game) => HttpResponse::Ok().body(position::compact(game)),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Analysis {
+    #[serde(flatten)]
+    board: BoardView,
+    /// Columns each team could win in with their next piece
+    winning_moves: HashMap<String, Vec<usize>>,
+}
+
+#[post("/analyze")]
+async fn analyze(
+    position: String,
+    query: Query<PositionQuery>,
+    teams: SharedTeams,
+) -> HttpResponse {
+    let roster = teams.read().await.clone();
+    match position::parse(&position, query.connect(), roster) {
+        Ok(game) => HttpResponse::Ok().json(Analysis {
+            board: BoardView::from(&game),
+            winning_moves: position::winning_moves(&game)
+                .into_iter()
+                .map(|(piece, columns)| (game.roster.name(piece).to_string(), columns))
+                .collect(),
+        }),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[post("/load")]
+async fn load_board(
+    position: String,
+    query: Query<PositionQuery>,
+    games: SharedGames,
+    teams: SharedTeams,
+    key: SharedSeatKey,
+    request: HttpRequest,
+) -> HttpResponse {
+    let format = Format::negotiate(&request, Format::Text);
+    let connect = query.connect();
+    let token = bearer_token(&request);
+    load(
+        &games,
+        &teams,
+        &DEFAULT_GAME,
+        &position,
+        connect,
+        &key,
+        token,
+        format,
+    )
+    .await
+}
+
+#[get("/export")]
+async fn export_board(games: SharedGames) -> HttpResponse {
+    export(&games, &DEFAULT_GAME).await
+}
+
+#[derive(Debug, Serialize)]
+struct GameSummary {
+    id: Uuid,
+    #[serde(flatten)]
+    config: BoardConfig,
+    idle_seconds: u64,
+}
+
+#[get("/games")]
+async fn list_games(games: SharedGames) -> Json<Vec<GameSummary>> {
+    let games = games.read().await;
+    let summaries = games
+        .sessions
+        .iter()
+        .map(|(id, session)| GameSummary {
+            id: *id,
+            config: session.game.config,
+            idle_seconds: session.last_active.elapsed().as_secs(),
+        })
+        .collect();
+    Json(summaries)
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedGame {
+    id: Uuid,
+}
+
+#[post("/games")]
+async fn create_game(
+    games: SharedGames,
+    teams: SharedTeams,
+    config: Query<BoardConfig>,
+) -> HttpResponse {
+    let roster = teams.read().await.clone();
+    if !config.is_valid(&roster) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let game = Game::with_config(config.into_inner(), roster);
+    let id = games.write().await.create(game);
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/12/games/{id}/board")))
+        .json(CreatedGame { id })
+}
+
+#[get("/games/{id}/board")]
+async fn show_game_board(id: Path<Uuid>, games: SharedGames, request: HttpRequest) -> HttpResponse {
+    show(&games, &id, Format::negotiate(&request, Format::Text)).await
+}
+
+#[post("/games/{id}/reset")]
+async fn reset_game_board(
+    id: Path<Uuid>,
+    games: SharedGames,
+    teams: SharedTeams,
+    key: SharedSeatKey,
+    config: Query<BoardConfig>,
+    request: HttpRequest,
+) -> HttpResponse {
+    let format = Format::negotiate(&request, Format::Text);
+    let token = bearer_token(&request);
+    reset(
+        &games,
+        &teams,
+        &id,
+        config.into_inner(),
+        &key,
+        token,
+        format,
+    )
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct GamePlaceParams {
+    id: Uuid,
     team: String,
     column: String,
 }
 
-#[post("/place/{team}/{column}")]
-async fn place_piece(params: Path<PlaceParams>, game: SharedGame) -> Either<String, HttpResponse> {
-    let piece_o = match params.team.as_str() {
-        "milk" => Some(Piece::Milk),
-        "cookie" => Some(Piece::Cookie),
-        _ => None,
+#[allow(clippy::too_many_arguments)]
+#[post("/games/{id}/place/{team}/{column}")]
+async fn place_game_piece(
+    params: Path<GamePlaceParams>,
+    query: Query<OpponentQuery>,
+    games: SharedGames,
+    tournaments: SharedTournaments,
+    rng: SharedRng,
+    pool: SharedDBPool,
+    key: SharedSeatKey,
+    request: HttpRequest,
+) -> HttpResponse {
+    let GamePlaceParams { id, team, column } = params.into_inner();
+    let params = PlaceParams { team, column };
+    let opponent = query.opponent.map(|difficulty| (difficulty, &rng));
+    let token = bearer_token(&request);
+    let format = Format::negotiate(&request, Format::Text);
+    let response = place(&games, &id, &params, &key, token, opponent, &pool, format).await;
+    // The move might have finished a tournament match
+    if response.status().is_success() {
+        tournament::pick_up_result(&tournaments, &games, &key, &id).await;
+    }
+    response
+}
+
+#[post("/games/{id}/undo")]
+async fn undo_game_move(
+    id: Path<Uuid>,
+    games: SharedGames,
+    key: SharedSeatKey,
+    request: HttpRequest,
+) -> HttpResponse {
+    let format = Format::negotiate(&request, Format::Text);
+    undo(&games, &id, &key, bearer_token(&request), format).await
+}
+
+#[get("/games/{id}/history")]
+async fn show_game_history(
+    id: Path<Uuid>,
+    games: SharedGames,
+    request: HttpRequest,
+) -> HttpResponse {
+    history(&games, &id, &request).await
+}
+
+#[get("/games/{id}/replay/{moves}")]
+async fn replay_game_board(
+    params: Path<(Uuid, usize)>,
+    games: SharedGames,
+    request: HttpRequest,
+) -> HttpResponse {
+    let (id, moves) = params.into_inner();
+    replay(
+        &games,
+        &id,
+        moves,
+        Format::negotiate(&request, Format::Text),
+    )
+    .await
+}
+
+#[post("/games/{id}/load")]
+async fn load_game_board(
+    id: Path<Uuid>,
+    position: String,
+    query: Query<PositionQuery>,
+    games: SharedGames,
+    teams: SharedTeams,
+    key: SharedSeatKey,
+    request: HttpRequest,
+) -> HttpResponse {
+    let format = Format::negotiate(&request, Format::Text);
+    let connect = query.connect();
+    let token = bearer_token(&request);
+    load(&games, &teams, &id, &position, connect, &key, token, format).await
+}
+
+#[get("/games/{id}/export")]
+async fn export_game_board(id: Path<Uuid>, games: SharedGames) -> HttpResponse {
+    export(&games, &id).await
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinParams {
+    id: Uuid,
+    team: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinQuery {
+    player: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct JoinedGame {
+    token: String,
+}
+
+#[post("/games/{id}/join/{team}")]
+async fn join_game(
+    params: Path<JoinParams>,
+    query: Query<JoinQuery>,
+    games: SharedGames,
+    key: SharedSeatKey,
+) -> HttpResponse {
+    let mut games = games.write().await;
+    let Some(session) = games.get_mut(&params.id) else {
+        return HttpResponse::NotFound().finish();
     };
+    if session.locked {
+        return HttpResponse::Forbidden().finish();
+    }
+    let Some(team) = session.game.roster.find_team(&params.team) else {
+        return HttpResponse::BadRequest().finish();
+    };
+    if !session.game.has_team(team) {
+        return HttpResponse::BadRequest().finish();
+    }
+    if session.seats.contains_key(&team) {
+        return HttpResponse::Conflict().finish();
+    }
 
-    if let Some(piece) = piece_o {
-        if let Ok(column) = params.column.parse::<usize>() {
-            if (1..=4).contains(&column) {
-                let mut game = game.write().await;
-                eprintln!("GAME STATE");
-                eprintln!("{game}");
-                eprintln!("Trying to add {piece} to column {column}");
+    let player = query
+        .into_inner()
+        .player
+        .unwrap_or_else(|| params.team.clone());
+    let token = session.seat(&params.id, team, player, &key);
 
-                if game.place(piece, column - 1) {
-                    return Either::Left(game.to_string());
-                }
+    HttpResponse::Ok().json(JoinedGame { token })
+}
 
-                return Either::Right(HttpResponse::ServiceUnavailable().body(game.to_string()));
-            }
+/// Give up a seat, so that someone else can take it
+#[post("/games/{id}/leave")]
+async fn leave_game(
+    id: Path<Uuid>,
+    games: SharedGames,
+    key: SharedSeatKey,
+    request: HttpRequest,
+) -> HttpResponse {
+    let mut games = games.write().await;
+    let Some(session) = games.get_mut(&id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let seat = match check_seated(session, &id, &key, bearer_token(&request)) {
+        Ok(seat) => seat,
+        Err(response) => return response,
+    };
+    session.seats.remove(&seat.team);
+    HttpResponse::NoContent().finish()
+}
+
+/// Header with the seed a random board was generated from, when one was
+/// asked for. Boards from the shared generator have no seed to give back.
+const SEED_HEADER: &str = "x-seed";
+
+#[derive(Debug, Deserialize)]
+struct RandomQuery {
+    /// Generate the board from this seed, rather than one drawn from the
+    /// shared generator
+    seed: Option<u64>,
+    /// Only generate positions that could come up in a real game
+    #[serde(default)]
+    reachable: bool,
+}
+
+impl RandomQuery {
+    fn generate(&self, config: BoardConfig, roster: Roster, rng: &mut StdRng) -> Game {
+        if self.reachable {
+            Game::random_reachable(config, roster, rng)
+        } else {
+            Game::random(config, roster, rng)
         }
     }
-
-    Either::Right(HttpResponse::BadRequest().finish())
 }
 
 #[get("/random-board")]
-async fn random_board(rng: SharedRng) -> String {
-    let mut rng = rng.lock().await;
-    Game::random(&mut rng).to_string()
+async fn random_board(
+    rng: SharedRng,
+    games: SharedGames,
+    teams: SharedTeams,
+    query: Query<RandomQuery>,
+    config: Query<BoardConfig>,
+    request: HttpRequest,
+) -> HttpResponse {
+    let roster = teams.read().await.clone();
+    if !config.is_valid(&roster) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let game = match query.seed {
+        Some(seed) => query.generate(*config, roster, &mut StdRng::seed_from_u64(seed)),
+        None => query.generate(*config, roster, &mut *rng.lock().await),
+    };
+
+    // Random boards belong with the rest of the `/12/board` routes
+    if let Some(session) = games.read().await.get_session(&DEFAULT_GAME) {
+        session.publish_board(game.to_string(), GameEvent::RandomBoard);
+    }
+
+    let mut response = render(
+        &game,
+        Format::negotiate(&request, Format::Text),
+        StatusCode::OK,
+    );
+    if let Some(seed) = query.seed {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(SEED_HEADER), seed.into());
+    }
+    response
+}
+
+/// Send every update to a game down a websocket, until either side goes away
+async fn watch(
+    games: &SharedGames,
+    id: &Uuid,
+    request: &HttpRequest,
+    body: Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(mut updates) = games
+        .read()
+        .await
+        .get_session(id)
+        .map(|session| session.updates.subscribe())
+    else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let (response, mut socket, mut messages) = actix_ws::handle(request, body)?;
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(Update { board, event }) => {
+                        let event = serde_json::to_string(&event).expect("events should serialize");
+                        if socket.text(board).await.is_err() || socket.text(event).await.is_err() {
+                            return;
+                        }
+                    }
+                    // Missed some updates, but the next one has the whole board anyway
+                    Err(broadcast::error::RecvError::Lagged(_)) => (),
+                    // The game has expired
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                message = messages.recv() => match message {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if socket.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(reason))) => {
+                        let _ = socket.close(reason).await;
+                        return;
+                    }
+                    Some(Ok(_)) => (),
+                    Some(Err(_)) | None => break,
+                },
+            }
+        }
+        let _ = socket.close(None).await;
+    });
+
+    Ok(response)
+}
+
+#[get("/ws")]
+async fn watch_board(
+    games: SharedGames,
+    request: HttpRequest,
+    body: Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    watch(&games, &DEFAULT_GAME, &request, body).await
+}
+
+#[get("/games/{id}/ws")]
+async fn watch_game_board(
+    id: Path<Uuid>,
+    games: SharedGames,
+    request: HttpRequest,
+    body: Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    watch(&games, &id, &request, body).await
 }
 
 pub fn scope() -> Scope {
@@ -261,20 +1607,62 @@ pub fn scope() -> Scope {
         .service(reset_board)
         .service(place_piece)
         .service(random_board)
+        .service(watch_board)
+        .service(undo_move)
+        .service(show_history)
+        .service(replay_board)
+        .service(analyze)
+        .service(stats::leaderboard)
+        .service(stats::stats)
+        .service(load_board)
+        .service(export_board)
+        .service(list_games)
+        .service(create_game)
+        .service(show_game_board)
+        .service(reset_game_board)
+        .service(place_game_piece)
+        .service(undo_game_move)
+        .service(show_game_history)
+        .service(replay_game_board)
+        .service(load_game_board)
+        .service(export_game_board)
+        .service(join_game)
+        .service(leave_game)
+        .service(watch_game_board)
+        .service(team::list_teams)
+        .service(team::register_team)
+        .service(team::remove_team)
+        .service(tournament::list_tournaments)
+        .service(tournament::create_tournament)
+        .service(tournament::show_tournament)
+        .service(tournament::add_player)
+        .service(tournament::start_tournament)
+        .service(tournament::take_seats)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Piece::*;
     use super::*;
 
+    const COOKIE: Piece = Piece::COOKIE;
+    const MILK: Piece = Piece::MILK;
+
+    fn from_board(board: [[Option<Piece>; 4]; 4]) -> Game {
+        Game {
+            config: BoardConfig::default(),
+            roster: Roster::default(),
+            board: board.map(Vec::from).to_vec(),
+            moves: Vec::new(),
+            start: None,
+            first_turn: COOKIE,
+        }
+    }
+
     #[test]
     fn game_displays_correctly() {
         for (game, expected) in [
             (
-                Game {
-                    board: [[Some(Cookie); 4], [None; 4], [None; 4], [None; 4]],
-                },
+                from_board([[Some(COOKIE); 4], [None; 4], [None; 4], [None; 4]]),
                 "\
 ⬜🍪⬛⬛⬛⬜
 ⬜🍪⬛⬛⬛⬜
@@ -285,14 +1673,12 @@ mod tests {
 ",
             ),
             (
-                Game {
-                    board: [
-                        [Some(Milk), Some(Cookie), Some(Cookie), Some(Cookie)],
-                        [Some(Cookie), Some(Milk), Some(Milk), Some(Milk)],
-                        [Some(Milk), Some(Cookie), Some(Cookie), Some(Cookie)],
-                        [Some(Cookie), Some(Milk), Some(Milk), Some(Milk)],
-                    ],
-                },
+                from_board([
+                    [Some(MILK), Some(COOKIE), Some(COOKIE), Some(COOKIE)],
+                    [Some(COOKIE), Some(MILK), Some(MILK), Some(MILK)],
+                    [Some(MILK), Some(COOKIE), Some(COOKIE), Some(COOKIE)],
+                    [Some(COOKIE), Some(MILK), Some(MILK), Some(MILK)],
+                ]),
                 "\
 ⬜🥛🍪🥛🍪⬜
 ⬜🍪🥛🍪🥛⬜
@@ -303,14 +1689,12 @@ No winner.
 ",
             ),
             (
-                Game {
-                    board: [
-                        [None, None, None, Some(Cookie)],
-                        [None, None, Some(Cookie), Some(Milk)],
-                        [None, Some(Cookie), Some(Milk), Some(Milk)],
-                        [Some(Cookie), Some(Milk), Some(Milk), Some(Milk)],
-                    ],
-                },
+                from_board([
+                    [None, None, None, Some(COOKIE)],
+                    [None, None, Some(COOKIE), Some(MILK)],
+                    [None, Some(COOKIE), Some(MILK), Some(MILK)],
+                    [Some(COOKIE), Some(MILK), Some(MILK), Some(MILK)],
+                ]),
                 "\
 ⬜⬛⬛⬛🍪⬜
 ⬜⬛⬛🍪🥛⬜
@@ -328,22 +1712,371 @@ No winner.
     #[test]
     fn can_add_pieces_to_game() {
         let mut game = Game::new();
-        game.place(Milk, 1);
-        game.place(Cookie, 1);
-        game.place(Milk, 2);
-        game.place(Cookie, 3);
-        game.place(Milk, 2);
+        game.place(MILK, 0);
+        game.place(COOKIE, 0);
+        game.place(MILK, 1);
+        game.place(COOKIE, 2);
+        game.place(MILK, 1);
 
         assert_eq!(
-            Game {
-                board: [
-                    [None, None, Some(Cookie), Some(Milk)],
-                    [None, None, Some(Milk), Some(Milk)],
-                    [None, None, None, Some(Cookie)],
-                    [None, None, None, None],
-                ]
+            from_board([
+                [None, None, Some(COOKIE), Some(MILK)],
+                [None, None, Some(MILK), Some(MILK)],
+                [None, None, None, Some(COOKIE)],
+                [None, None, None, None],
+            ])
+            .board,
+            game.board
+        );
+    }
+
+    #[test]
+    fn moves_can_be_undone_and_replayed() {
+        let mut game = Game::new();
+        for (piece, column) in [(COOKIE, 0), (MILK, 0), (COOKIE, 1), (MILK, 3)] {
+            game.place(piece, column);
+        }
+
+        let replayed = game.replay(2).unwrap();
+        assert_eq!(
+            "\
+⬜⬛⬛⬛⬛⬜
+⬜⬛⬛⬛⬛⬜
+⬜🥛⬛⬛⬛⬜
+⬜🍪⬛⬛⬛⬜
+⬜⬜⬜⬜⬜⬜
+",
+            replayed.to_string()
+        );
+        assert!(game.replay(5).is_none());
+
+        assert_eq!(
+            Some(Move {
+                team: MILK,
+                column: 4
+            }),
+            game.undo()
+        );
+        assert_eq!(
+            "\
+[Width \"4\"]
+[Height \"4\"]
+[Connect \"4\"]
+[Result \"*\"]
+
+1. 🍪1 2. 🥛1 3. 🍪2 *
+",
+            game.notation()
+        );
+    }
+
+    #[test]
+    fn loaded_games_replay_from_the_loaded_position() {
+        let mut game = position::parse("4/4/4/c3", 4, Roster::default()).unwrap();
+        assert!(!game.is_turn(COOKIE));
+        assert!(game.undo().is_none());
+        game.place(MILK, 1);
+        game.place(COOKIE, 1);
+
+        let start = game.replay(0).unwrap();
+        assert_eq!(Some("4/4/4/c3".to_owned()), start.start_position());
+        assert!(start.is_turn(MILK));
+        assert_eq!(game, game.replay(2).unwrap());
+        assert_eq!(
+            "\
+[Width \"4\"]
+[Height \"4\"]
+[Connect \"4\"]
+[Position \"4/4/4/c3\"]
+[Result \"*\"]
+
+1. 🥛2 2. 🍪2 *
+",
+            game.notation()
+        );
+    }
+
+    #[test]
+    fn the_shared_generator_deals_the_same_first_board() {
+        let game = RandomQuery {
+            seed: None,
+            reachable: false,
+        }
+        .generate(
+            BoardConfig::default(),
+            Roster::default(),
+            &mut new_seeded_rng(),
+        );
+        assert_eq!(
+            "\
+⬜🍪🍪🍪🍪⬜
+⬜🥛🍪🍪🥛⬜
+⬜🥛🥛🥛🥛⬜
+⬜🍪🥛🍪🥛⬜
+⬜⬜⬜⬜⬜⬜
+🍪 wins!
+",
+            game.to_string()
+        );
+    }
+
+    #[test]
+    fn connect_four_on_a_larger_board() {
+        let mut game = Game::with_config(
+            BoardConfig {
+                width: 7,
+                height: 6,
+                connect: 4,
+                teams: 2,
             },
-            game
+            Roster::default(),
+        );
+        for (piece, column) in [
+            (COOKIE, 0),
+            (MILK, 1),
+            (COOKIE, 1),
+            (MILK, 2),
+            (MILK, 2),
+            (COOKIE, 2),
+            (MILK, 3),
+            (MILK, 3),
+            (MILK, 3),
+        ] {
+            game.place(piece, column);
+        }
+        assert!(matches!(game.get_state(), GameState::NotEnded));
+
+        game.place(COOKIE, 3);
+
+        assert_eq!(
+            "\
+⬜⬛⬛⬛⬛⬛⬛⬛⬜
+⬜⬛⬛⬛⬛⬛⬛⬛⬜
+⬜⬛⬛⬛🍪⬛⬛⬛⬜
+⬜⬛⬛🍪🥛⬛⬛⬛⬜
+⬜⬛🍪🥛🥛⬛⬛⬛⬜
+⬜🍪🥛🥛🥛⬛⬛⬛⬜
+⬜⬜⬜⬜⬜⬜⬜⬜⬜
+🍪 wins!
+",
+            game.to_string()
         );
     }
+
+    #[test]
+    fn three_teams_take_turns_and_any_of_them_can_win() {
+        let roster = Roster::default();
+        let carrot = roster.find_team("carrot").unwrap();
+        let config = BoardConfig {
+            teams: 3,
+            ..BoardConfig::default()
+        };
+        assert!(config.is_valid(&roster));
+        let mut game = Game::with_config(config, roster);
+
+        for column in [0, 1, 2, 0, 1, 2, 0, 1, 2, 3, 3, 2] {
+            let piece = game.last_turn().map_or(COOKIE, |p| p.next(3));
+            assert!(game.is_turn(piece));
+            assert!(game.place(piece, column));
+        }
+
+        assert!(matches!(game.get_state(), GameState::Winner(w) if w == carrot));
+        assert!(game.to_string().ends_with("🥕 wins!\n"));
+    }
+
+    #[test]
+    fn invalid_board_configs_are_rejected() {
+        for (width, height, connect, teams) in [
+            (0, 6, 4, 2),
+            (7, 17, 4, 2),
+            (7, 6, 8, 2),
+            (7, 6, 1, 2),
+            (7, 6, 4, 1),
+            (7, 6, 4, 99),
+        ] {
+            let config = BoardConfig {
+                width,
+                height,
+                connect,
+                teams,
+            };
+            assert!(!config.is_valid(&Roster::default()));
+        }
+    }
+
+    #[test]
+    fn board_view_serializes_rows_and_heights() {
+        let mut game = Game::new();
+        game.place(COOKIE, 0);
+        game.place(MILK, 0);
+        game.place(COOKIE, 3);
+
+        assert_eq!(
+            serde_json::json!({
+                "width": 4,
+                "height": 4,
+                "connect": 4,
+                "teams": 2,
+                "cells": [
+                    [null, null, null, null],
+                    [null, null, null, null],
+                    ["milk", null, null, null],
+                    ["cookie", null, null, "cookie"],
+                ],
+                "state": "ongoing",
+                "winner": null,
+                "heights": [2, 0, 0, 1],
+            }),
+            serde_json::to_value(BoardView::from(&game)).unwrap()
+        );
+    }
+
+    #[test]
+    fn random_reachable_boards_are_legal_and_reproducible() {
+        let config = BoardConfig {
+            width: 7,
+            height: 6,
+            connect: 4,
+            teams: 2,
+        };
+        for seed in 0..20 {
+            let generate = || {
+                let mut rng = StdRng::seed_from_u64(seed);
+                Game::random_reachable(config, Roster::default(), &mut rng)
+            };
+            let game = generate();
+
+            let position = position::compact(&game);
+            assert!(position::parse(&position, config.connect, Roster::default()).is_ok());
+            assert_eq!(game, generate());
+        }
+    }
+
+    #[test]
+    fn games_are_kept_apart_and_expire_when_idle() {
+        let mut games = Games::new();
+        let small = BoardConfig {
+            width: 5,
+            height: 5,
+            connect: 4,
+            teams: 2,
+        };
+        let first = games.create(Game::with_config(small, Roster::default()));
+        let second = games.create(Game::new());
+        let kept = games.create_kept(Game::new());
+        assert_ne!(first, second);
+
+        games.get_mut(&first).unwrap().game.place(COOKIE, 0);
+        assert_eq!(small, games.get(&first).unwrap().config);
+        assert_eq!(1, games.get(&first).unwrap().moves.len());
+        assert!(games.get(&second).unwrap().moves.is_empty());
+        assert!(games.get(&Uuid::new_v4()).is_none());
+
+        let now = Instant::now();
+        games.get_mut(&second).unwrap().last_active = now + MAX_IDLE;
+        games.expire_idle(now + MAX_IDLE);
+
+        // Only the idle game that's allowed to expire is gone
+        assert!(games.get(&first).is_none());
+        assert!(games.get(&second).is_some());
+        assert!(games.get(&kept).is_some());
+        assert!(games.get(&DEFAULT_GAME).is_some());
+    }
+
+    #[test]
+    fn seated_players_take_turns_from_their_own_seats() {
+        let key = SeatKey(HS256Key::generate());
+        let id = Uuid::new_v4();
+        let mut session = Session::new(Game::new(), true);
+        let cookie = session.seat(&id, COOKIE, "alice".into(), &key);
+        let milk = session.seat(&id, MILK, "bob".into(), &key);
+        let status = |session: &Session, piece, token: Option<&str>| {
+            check_turn(session, &id, piece, &key, token, false, Format::Text)
+                .map_or_else(|response| response.status(), |()| StatusCode::OK)
+        };
+
+        assert_eq!(StatusCode::UNAUTHORIZED, status(&session, COOKIE, None));
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(&session, COOKIE, Some("x"))
+        );
+        // Playing for the other team, or at another game
+        assert_eq!(StatusCode::FORBIDDEN, status(&session, COOKIE, Some(&milk)));
+        let elsewhere =
+            Session::new(Game::new(), true).seat(&Uuid::new_v4(), COOKIE, "alice".into(), &key);
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(&session, COOKIE, Some(&elsewhere))
+        );
+
+        assert_eq!(StatusCode::OK, status(&session, COOKIE, Some(&cookie)));
+        session.game.place(COOKIE, 0);
+        assert_eq!(
+            StatusCode::CONFLICT,
+            status(&session, COOKIE, Some(&cookie))
+        );
+        assert_eq!(StatusCode::OK, status(&session, MILK, Some(&milk)));
+
+        // A token stops working once someone else has the seat
+        session.seat(&id, MILK, "carol".into(), &key);
+        assert_eq!(StatusCode::FORBIDDEN, status(&session, MILK, Some(&milk)));
+    }
+
+    #[test]
+    fn seats_are_freed_when_they_run_out_or_the_game_restarts() {
+        let key = SeatKey(HS256Key::generate());
+        let id = Uuid::new_v4();
+        let mut session = Session::new(Game::new(), true);
+        assert!(check_restart(&session, &id, &key, None).is_ok());
+        session.seat(&id, COOKIE, "alice".into(), &key);
+
+        session.release_expired_seats(Instant::now());
+        assert!(session.seats.contains_key(&COOKIE));
+        session.release_expired_seats(Instant::now() + SEAT_LIFETIME);
+        assert!(session.seats.is_empty());
+
+        // The game doesn't open up to anyone just because nobody's sitting
+        // at it any more
+        let status = |session: &Session, token: Option<&str>| {
+            let turn = check_turn(session, &id, COOKIE, &key, token, false, Format::Text);
+            let restart = check_restart(session, &id, &key, token);
+            [turn, restart]
+                .map(|result| result.map_or_else(|response| response.status(), |()| StatusCode::OK))
+        };
+        assert_eq!([StatusCode::UNAUTHORIZED; 2], status(&session, None));
+
+        // Only someone sitting at the game can start it over
+        let alice = session.seat(&id, COOKIE, "alice".into(), &key);
+        let bob = Session::new(Game::new(), true).seat(&id, MILK, "bob".into(), &key);
+        assert_eq!([StatusCode::FORBIDDEN; 2], status(&session, Some(&bob)));
+        assert_eq!([StatusCode::OK; 2], status(&session, Some(&alice)));
+
+        session.replace(Game::new());
+        assert!(session.seats.is_empty());
+        assert_eq!([StatusCode::FORBIDDEN; 2], status(&session, Some(&alice)));
+    }
+
+    #[test]
+    fn locked_games_keep_their_seats_and_board() {
+        let key = SeatKey(HS256Key::generate());
+        let lifetime = SEAT_LIFETIME * 14;
+        let mut games = Games::new();
+        let id = games.create_locked(Game::new(), lifetime);
+        let session = games.get_mut(&id).unwrap();
+        let alice = session.seat(&id, COOKIE, "alice".into(), &key);
+
+        // Not even a player at the table can restart it
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            check_restart(session, &id, &key, Some(&alice))
+                .unwrap_err()
+                .status()
+        );
+
+        session.release_expired_seats(Instant::now() + SEAT_LIFETIME);
+        assert!(session.seats.contains_key(&COOKIE));
+        session.release_expired_seats(Instant::now() + lifetime);
+        assert!(session.seats.is_empty());
+        assert!(check_turn(session, &id, COOKIE, &key, None, false, Format::Text).is_err());
+    }
 }
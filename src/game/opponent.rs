@@ -0,0 +1,296 @@
+//! A computer player for the cookie/milk game, searching ahead with minimax
+//! and alpha-beta pruning. With more than two teams, it plays as if all the
+//! others were out to get it.
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+
+use super::{Game, GameState, Piece, DIRECTIONS};
+
+/// Score of a won position, before adjusting for how soon the win comes
+const WIN: i32 = 1_000_000;
+
+/// Roughly how many cells the opponent gets to look at for each move, across
+/// all the positions it searches. Bigger boards have more positions at every
+/// step and more cells in each, so it can't look as far ahead on them.
+const SEARCH_BUDGET: usize = 5_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(super) enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    /// How many moves ahead the opponent looks in `game`, as far as the
+    /// search budget goes
+    fn depth(self, game: &Game) -> i32 {
+        let wanted = match self {
+            Self::Easy => 1,
+            Self::Medium => 3,
+            Self::Hard => 6,
+        };
+        let cells = game.config.width * game.config.height;
+        let columns = game.open_columns().len().max(1);
+
+        let mut depth = 1;
+        let mut positions = columns;
+        while depth < wanted
+            && positions.saturating_mul(columns).saturating_mul(cells) <= SEARCH_BUDGET
+        {
+            positions *= columns;
+            depth += 1;
+        }
+        depth
+    }
+}
+
+/// Choose a column (starting at 0) for `piece` to play. Equally good moves
+/// are picked between at random, so the choice only depends on the state of
+/// `rng`.
+pub(super) fn choose_move(
+    game: &Game,
+    piece: Piece,
+    difficulty: Difficulty,
+    rng: &mut StdRng,
+) -> Option<usize> {
+    let depth = difficulty.depth(game);
+    let mut best_score = -i32::MAX;
+    let mut best_columns = Vec::new();
+
+    for column in playable_columns(game) {
+        let mut next = game.clone();
+        next.place(piece, column);
+        let score = minimax(
+            &next,
+            piece,
+            piece.next(game.config.teams),
+            depth - 1,
+            -i32::MAX,
+            i32::MAX,
+        );
+
+        if score > best_score {
+            best_score = score;
+            best_columns.clear();
+        }
+        if score == best_score {
+            best_columns.push(column);
+        }
+    }
+
+    best_columns.choose(rng).copied()
+}
+
+/// Score the game from the point of view of `us`, with `to_move` about to
+/// play
+fn minimax(
+    game: &Game,
+    us: Piece,
+    to_move: Piece,
+    depth: i32,
+    mut alpha: i32,
+    mut beta: i32,
+) -> i32 {
+    match game.get_state() {
+        // Prefer winning sooner and losing later
+        GameState::Winner(w) if w == us => return WIN + depth,
+        GameState::Winner(_) => return -WIN - depth,
+        GameState::Draw => return 0,
+        GameState::NotEnded => (),
+    }
+
+    if depth <= 0 {
+        return evaluate(game, us);
+    }
+
+    let ours = to_move == us;
+    let mut best = if ours { -i32::MAX } else { i32::MAX };
+    for column in playable_columns(game) {
+        let mut next = game.clone();
+        next.place(to_move, column);
+        let score = minimax(
+            &next,
+            us,
+            to_move.next(game.config.teams),
+            depth - 1,
+            alpha,
+            beta,
+        );
+
+        if ours {
+            best = best.max(score);
+            alpha = alpha.max(score);
+        } else {
+            best = best.min(score);
+            beta = beta.min(score);
+        }
+        if alpha >= beta {
+            break;
+        }
+    }
+    best
+}
+
+/// Columns that still have space, closest to the centre first, since those
+/// moves tend to be better and get the most out of pruning
+fn playable_columns(game: &Game) -> Vec<usize> {
+    let width = game.config.width;
+    let mut columns = game.open_columns();
+    columns.sort_by_key(|&column| (2 * column).abs_diff(width - 1));
+    columns
+}
+
+/// Rough score of an unfinished game: every line that could still be
+/// completed counts for whoever has pieces in it, more so the fuller it is
+fn evaluate(game: &Game, us: Piece) -> i32 {
+    // However many lines there are, together they never add up to a win
+    let lines = DIRECTIONS.len() * game.config.width * game.config.height;
+    let cap = WIN / i32::try_from(lines).unwrap_or(i32::MAX);
+    let mut score = 0;
+
+    for direction in DIRECTIONS {
+        for column in 0..game.config.width {
+            for row in 0..game.config.height {
+                let Some(line) = cells_from(game, column, row, direction) else {
+                    continue;
+                };
+
+                // Lines with more than one team's pieces can't be completed
+                let mut pieces = line.iter().flatten();
+                let Some(&team) = pieces.next() else {
+                    continue;
+                };
+                if !pieces.all(|&p| p == team) {
+                    continue;
+                }
+
+                let n = line.iter().flatten().count();
+                let weight = u32::try_from(n)
+                    .ok()
+                    .and_then(|n| 4_i32.checked_pow(n))
+                    .map_or(cap, |weight| weight.min(cap));
+                if team == us {
+                    score += weight;
+                } else {
+                    score -= weight;
+                }
+            }
+        }
+    }
+
+    score
+}
+
+/// The cells of a line of winning length starting from the given cell, if it
+/// fits on the board
+fn cells_from(
+    game: &Game,
+    column: usize,
+    row: usize,
+    (dc, dr): (isize, isize),
+) -> Option<Vec<Option<Piece>>> {
+    let (mut column, mut row) = (column, row);
+    let mut line = vec![game.board[column][row]];
+    for _ in 1..game.config.connect {
+        column = column.checked_add_signed(dc)?;
+        row = row.checked_add_signed(dr)?;
+        line.push(*game.board.get(column)?.get(row)?);
+    }
+    Some(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::super::team::Roster;
+    use super::super::BoardConfig;
+    use super::*;
+
+    const COOKIE: Piece = Piece::COOKIE;
+    const MILK: Piece = Piece::MILK;
+
+    #[test]
+    fn opponent_takes_a_winning_move() {
+        let mut game = Game::new();
+        for (piece, column) in [(COOKIE, 2), (MILK, 0), (COOKIE, 2), (MILK, 0), (COOKIE, 2)] {
+            game.place(piece, column);
+        }
+
+        let mut rng = StdRng::seed_from_u64(2024);
+        assert_eq!(
+            Some(2),
+            choose_move(&game, COOKIE, Difficulty::Easy, &mut rng)
+        );
+    }
+
+    #[test]
+    fn opponent_blocks_a_winning_move() {
+        let mut game = Game::new();
+        for (piece, column) in [(COOKIE, 2), (MILK, 0), (COOKIE, 2), (MILK, 1), (COOKIE, 2)] {
+            game.place(piece, column);
+        }
+
+        for difficulty in [Difficulty::Medium, Difficulty::Hard] {
+            let mut rng = StdRng::seed_from_u64(2024);
+            assert_eq!(Some(2), choose_move(&game, MILK, difficulty, &mut rng));
+        }
+    }
+
+    #[test]
+    fn opponent_is_deterministic_for_a_given_seed() {
+        let game = Game::new();
+        let moves = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10)
+                .map(|_| choose_move(&game, COOKIE, Difficulty::Easy, &mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(moves(2024), moves(2024));
+    }
+
+    #[test]
+    fn long_lines_never_score_like_a_win() {
+        let mut game = Game::with_config(
+            BoardConfig {
+                width: 16,
+                height: 16,
+                connect: 16,
+                teams: 2,
+            },
+            Roster::default(),
+        );
+        for column in 0..15 {
+            for _ in 0..15 {
+                game.place(COOKIE, column);
+            }
+        }
+
+        let score = evaluate(&game, COOKIE);
+        assert!(0 < score && score < WIN);
+        assert_eq!(-score, evaluate(&game, MILK));
+    }
+
+    #[test]
+    fn opponent_looks_less_far_ahead_on_bigger_boards() {
+        assert_eq!(6, Difficulty::Hard.depth(&Game::new()));
+        assert_eq!(1, Difficulty::Easy.depth(&Game::new()));
+
+        let big = Game::with_config(
+            BoardConfig {
+                width: 16,
+                height: 16,
+                connect: 4,
+                teams: 2,
+            },
+            Roster::default(),
+        );
+        let depth = Difficulty::Hard.depth(&big);
+        assert!((1..6).contains(&depth));
+    }
+}
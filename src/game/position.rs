@@ -0,0 +1,307 @@
+//! Loading games from a written down position, either drawn the way the
+//! board is displayed or in a compact FEN-like notation, and looking at what
+//! can happen next
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::team::Roster;
+use super::{BoardConfig, Game, GameState, Piece, EMPTY, WALL};
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum PositionError {
+    /// A row, or the whole board, isn't shaped like a board
+    Malformed,
+    /// A cell that isn't a piece or empty
+    UnknownCell(String),
+    UnevenRows,
+    UnsupportedSize,
+    /// A piece sitting on top of an empty cell
+    Floating {
+        column: usize,
+    },
+    /// One team has had more than one turn more than another
+    UnevenCounts,
+}
+
+impl Display for PositionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Malformed => write!(f, "Malformed board"),
+            Self::UnknownCell(c) => write!(f, "Unknown cell {c}"),
+            Self::UnevenRows => write!(f, "Rows have different lengths"),
+            Self::UnsupportedSize => write!(f, "Unsupported board size"),
+            Self::Floating { column } => write!(f, "Floating piece in column {column}"),
+            Self::UnevenCounts => write!(f, "Teams have played too many pieces"),
+        }
+    }
+}
+
+/// Parse a position, needing `connect` in a row to win, with pieces from the
+/// teams in `roster`. Anything with walls is taken to be a board as
+/// displayed, otherwise the compact notation. The game is between as many
+/// teams as it takes to cover every piece on the board, and at least two.
+pub(super) fn parse(input: &str, connect: usize, roster: Roster) -> Result<Game, PositionError> {
+    let rows = if input.contains(WALL) {
+        parse_drawn(input, &roster)?
+    } else {
+        parse_compact(input, &roster)?
+    };
+
+    let width = rows.first().map_or(0, Vec::len);
+    if rows.iter().any(|row| row.len() != width) {
+        return Err(PositionError::UnevenRows);
+    }
+
+    let teams = rows
+        .iter()
+        .flatten()
+        .flatten()
+        .map(|piece| piece.0 + 1)
+        .max()
+        .unwrap_or_default()
+        .max(BoardConfig::default().teams);
+    let config = BoardConfig {
+        width,
+        height: rows.len(),
+        connect,
+        teams,
+    };
+    if !config.is_valid(&roster) {
+        return Err(PositionError::UnsupportedSize);
+    }
+
+    let mut game = Game::with_config(config, roster);
+    for (row, cells) in rows.into_iter().enumerate() {
+        for (column, cell) in cells.into_iter().enumerate() {
+            game.board[column][row] = cell;
+        }
+    }
+
+    check(&game)?;
+    let next = next_turn(&game);
+    game.set_start(next);
+    Ok(game)
+}
+
+/// Rows of a board drawn like `Display` does, ignoring anything after the
+/// bottom wall. Each cell is one grapheme, since a team's emoji can be made of
+/// several characters.
+fn parse_drawn(input: &str, roster: &Roster) -> Result<Vec<Vec<Option<Piece>>>, PositionError> {
+    let empty = EMPTY.to_string();
+    let mut rows = Vec::new();
+
+    for line in input.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if line.chars().all(|c| c == WALL) {
+            return Ok(rows);
+        }
+
+        let inner = line
+            .strip_prefix(WALL)
+            .and_then(|l| l.strip_suffix(WALL))
+            .ok_or(PositionError::Malformed)?;
+        let row = inner
+            .graphemes(true)
+            .map(|cell| {
+                if cell == empty {
+                    Ok(None)
+                } else {
+                    roster
+                        .find_emoji(cell)
+                        .map(Some)
+                        .ok_or_else(|| PositionError::UnknownCell(cell.to_string()))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        rows.push(row);
+    }
+
+    // Never found the bottom of the board
+    Err(PositionError::Malformed)
+}
+
+/// Rows in the compact notation: rows from the top separated by `/`, with a
+/// letter for each piece and a number for each run of empty cells
+fn parse_compact(input: &str, roster: &Roster) -> Result<Vec<Vec<Option<Piece>>>, PositionError> {
+    input
+        .trim()
+        .split('/')
+        .map(|line| {
+            let mut row = Vec::new();
+            let mut empty = 0;
+            for c in line.chars() {
+                if let Some(digit) = c.to_digit(10) {
+                    empty = empty * 10 + digit as usize;
+                    if empty > BoardConfig::MAX_SIZE {
+                        return Err(PositionError::UnsupportedSize);
+                    }
+                    continue;
+                }
+                row.extend(std::iter::repeat_n(None, empty));
+                empty = 0;
+                row.push(Some(
+                    roster
+                        .find_letter(c)
+                        .ok_or_else(|| PositionError::UnknownCell(c.to_string()))?,
+                ));
+            }
+            row.extend(std::iter::repeat_n(None, empty));
+            Ok(row)
+        })
+        .collect()
+}
+
+/// Check that the position could have come from a real game
+fn check(game: &Game) -> Result<(), PositionError> {
+    for (i, column) in game.board.iter().enumerate() {
+        // From the top, once there's a piece there's nothing but pieces
+        let first_piece = column
+            .iter()
+            .position(Option::is_some)
+            .unwrap_or(column.len());
+        if column[first_piece..].iter().any(Option::is_none) {
+            return Err(PositionError::Floating { column: i + 1 });
+        }
+    }
+
+    let counts = piece_counts(game);
+    let counts =
+        (0..game.config.teams).map(|team| counts.get(&Piece(team)).copied().unwrap_or_default());
+    if counts.clone().max().unwrap_or_default() - counts.min().unwrap_or_default() > 1 {
+        return Err(PositionError::UnevenCounts);
+    }
+
+    Ok(())
+}
+
+/// The team to play next: the first that has played fewer pieces than the
+/// others, or the first team if they've all played as many
+fn next_turn(game: &Game) -> Piece {
+    let counts = piece_counts(game);
+    let count = |team| counts.get(&Piece(team)).copied().unwrap_or_default();
+    let most = (0..game.config.teams).map(count).max().unwrap_or_default();
+    (0..game.config.teams)
+        .find(|&team| count(team) < most)
+        .map_or(Piece::COOKIE, Piece)
+}
+
+fn piece_counts(game: &Game) -> HashMap<Piece, usize> {
+    let mut counts = HashMap::new();
+    for piece in game.board.iter().flatten().flatten() {
+        *counts.entry(*piece).or_default() += 1;
+    }
+    counts
+}
+
+/// Write the position in the compact notation
+pub(super) fn compact(game: &Game) -> String {
+    (0..game.config.height)
+        .map(|row| {
+            let mut line = String::new();
+            let mut empty = 0;
+            for column in &game.board {
+                match column[row] {
+                    Some(piece) => {
+                        if empty > 0 {
+                            line.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        line.push(game.roster.letter(piece));
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                line.push_str(&empty.to_string());
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Columns (starting at 1) where each team would win straight away
+pub(super) fn winning_moves(game: &Game) -> HashMap<Piece, Vec<usize>> {
+    let mut moves: HashMap<_, _> = (0..game.config.teams)
+        .map(|team| (Piece(team), Vec::new()))
+        .collect();
+    if !matches!(game.get_state(), GameState::NotEnded) {
+        return moves;
+    }
+
+    for (piece, columns) in &mut moves {
+        for column in 0..game.config.width {
+            let mut next = game.clone();
+            if next.place(*piece, column)
+                && matches!(next.get_state(), GameState::Winner(w) if w == *piece)
+            {
+                columns.push(column + 1);
+            }
+        }
+    }
+    moves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIE: Piece = Piece::COOKIE;
+    const MILK: Piece = Piece::MILK;
+
+    #[test]
+    fn drawn_and_compact_positions_agree() {
+        let drawn = "\
+⬜⬛⬛⬛⬛⬜
+⬜⬛⬛⬛⬛⬜
+⬜🥛⬛⬛⬛⬜
+⬜🍪🥛⬛🍪⬜
+⬜⬜⬜⬜⬜⬜
+";
+        let game = parse(drawn, 4, Roster::default()).unwrap();
+
+        assert_eq!(drawn, game.to_string());
+        assert_eq!("4/4/m3/cm1c", compact(&game));
+        assert_eq!(game, parse(&compact(&game), 4, Roster::default()).unwrap());
+    }
+
+    #[test]
+    fn impossible_positions_are_rejected() {
+        for (position, error) in [
+            ("4/c3/4/4", PositionError::Floating { column: 1 }),
+            ("4/4/c3/cc2", PositionError::UnevenCounts),
+            ("4/4/4/cx2", PositionError::UnknownCell("x".to_string())),
+            ("4/4/4/3", PositionError::UnevenRows),
+            ("⬜⬛⬛⬜\n⬜⬛⬛⬜\n", PositionError::Malformed),
+        ] {
+            assert_eq!(Err(error), parse(position, 4, Roster::default()));
+        }
+    }
+
+    #[test]
+    fn finds_winning_moves_for_both_teams() {
+        let game = parse("4/c3/cm2/cmm1", 4, Roster::default()).unwrap();
+        let moves = winning_moves(&game);
+
+        assert_eq!(vec![1], moves[&COOKIE]);
+        assert_eq!(Vec::<usize>::new(), moves[&MILK]);
+    }
+
+    #[test]
+    fn loaded_games_carry_on_with_the_team_that_has_played_least() {
+        for (position, teams, next) in [
+            ("4/4/4/4", 2, COOKIE),
+            ("4/4/4/c3", 2, MILK),
+            ("4/4/4/cm2", 2, COOKIE),
+            ("4/4/4/m3", 2, COOKIE),
+            ("4/4/4/cr2", 3, MILK),
+        ] {
+            let game = parse(position, 4, Roster::default()).unwrap();
+            assert_eq!(teams, game.config.teams, "{position}");
+            assert!(game.is_turn(next), "{position}");
+            assert!(game.moves.is_empty());
+        }
+    }
+}
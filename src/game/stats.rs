@@ -0,0 +1,144 @@
+//! Keeping track of finished games in the database, and what can be learned
+//! from them
+
+use std::collections::HashMap;
+
+use actix_web::web::Data;
+use actix_web::{get, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::types::Json;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use super::MoveView;
+
+pub(super) type SharedDBPool = Data<PgPool>;
+
+/// How a game ended, and who played it
+#[derive(Debug)]
+pub(super) struct GameResult {
+    pub(super) game: Uuid,
+    /// Name of the team that won, if anyone did
+    pub(super) winner: Option<String>,
+    pub(super) moves: Vec<MoveView>,
+    /// Who played for each team, by the team's name
+    pub(super) players: HashMap<String, String>,
+    pub(super) started_at: DateTime<Utc>,
+}
+
+impl GameResult {
+    /// Save the result in the background, so nobody has to wait on it
+    pub(super) fn record(self, pool: SharedDBPool) {
+        tokio::spawn(async move {
+            if let Err(err) = self.save(&pool).await {
+                tracing::error!(error = %err, game = %self.game, "couldn't record game result");
+            }
+        });
+    }
+
+    /// A game that's undone and finished again is still only one game, which
+    /// ended however it ended last
+    async fn save(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO game_results (id, game_id, winner, moves, players, started_at) \
+             VALUES ($1, $2, $3, $4, $5, $6) \
+             ON CONFLICT (game_id, started_at) DO UPDATE SET \
+                 winner = EXCLUDED.winner, \
+                 moves = EXCLUDED.moves, \
+                 players = EXCLUDED.players, \
+                 finished_at = EXCLUDED.finished_at",
+        )
+        .bind(Uuid::new_v4())
+        .bind(self.game)
+        .bind(&self.winner)
+        .bind(Json(&self.moves))
+        .bind(Json(&self.players))
+        .bind(self.started_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, FromRow)]
+struct PlayerStanding {
+    player: String,
+    games: i64,
+    wins: i64,
+    losses: i64,
+    draws: i64,
+}
+
+#[get("/leaderboard")]
+pub(super) async fn leaderboard(pool: SharedDBPool) -> HttpResponse {
+    match sqlx::query_as::<_, PlayerStanding>(
+        "SELECT p.value AS player, \
+             COUNT(*) AS games, \
+             COUNT(*) FILTER (WHERE r.winner = p.key) AS wins, \
+             COUNT(*) FILTER (WHERE r.winner <> p.key) AS losses, \
+             COUNT(*) FILTER (WHERE r.winner IS NULL) AS draws \
+         FROM game_results r, jsonb_each_text(r.players) p \
+         GROUP BY p.value \
+         ORDER BY wins DESC, draws DESC, player",
+    )
+    .fetch_all(&**pool)
+    .await
+    {
+        Ok(standings) => HttpResponse::Ok().json(standings),
+        Err(err) => {
+            tracing::error!(error = %err, "couldn't load leaderboard");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct Totals {
+    games: i64,
+    draws: i64,
+    average_seconds: Option<f64>,
+    average_moves: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    games: i64,
+    draws: i64,
+    /// Wins by team
+    wins: HashMap<String, i64>,
+    average_seconds: Option<f64>,
+    average_moves: Option<f64>,
+}
+
+#[get("/stats")]
+pub(super) async fn stats(pool: SharedDBPool) -> HttpResponse {
+    let totals = sqlx::query_as::<_, Totals>(
+        "SELECT COUNT(*) AS games, \
+             COUNT(*) FILTER (WHERE winner IS NULL) AS draws, \
+             AVG(EXTRACT(EPOCH FROM finished_at - started_at))::FLOAT8 AS average_seconds, \
+             AVG(jsonb_array_length(moves))::FLOAT8 AS average_moves \
+         FROM game_results",
+    )
+    .fetch_one(&**pool)
+    .await;
+    let wins = sqlx::query_as::<_, (String, i64)>(
+        "SELECT winner, COUNT(*) FROM game_results WHERE winner IS NOT NULL GROUP BY winner",
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match (totals, wins) {
+        (Ok(totals), Ok(wins)) => HttpResponse::Ok().json(Stats {
+            games: totals.games,
+            draws: totals.draws,
+            wins: wins.into_iter().collect(),
+            average_seconds: totals.average_seconds,
+            average_moves: totals.average_moves,
+        }),
+        (Err(err), _) | (_, Err(err)) => {
+            tracing::error!(error = %err, "couldn't load game stats");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
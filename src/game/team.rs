@@ -0,0 +1,226 @@
+//! The registry of teams that can play the game, each with its own piece
+
+use std::sync::Arc;
+
+use actix_web::http::header;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{delete, get, post, HttpResponse};
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::{Piece, EMPTY, WALL};
+
+/// Most teams there can be, counting the ones that are always there
+const MAX_TEAMS: usize = 16;
+
+/// The teams every registry starts with. Pieces like `Piece::COOKIE` refer
+/// to these, so they can't be removed.
+const BUILT_IN_TEAMS: usize = 3;
+
+/// Longest a team's emoji can be, in bytes. Emoji joined up from several
+/// codepoints, like flags and families, still fit comfortably.
+const MAX_EMOJI_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Team {
+    /// Name of the team, as used in routes
+    name: String,
+    /// What the team's pieces look like on the board, which is a single
+    /// character as far as readers are concerned
+    emoji: String,
+    /// Letter for the team's pieces in the compact position notation
+    letter: char,
+}
+
+impl Team {
+    fn new(name: &str, emoji: &str, letter: char) -> Self {
+        Self {
+            name: name.to_string(),
+            emoji: emoji.to_string(),
+            letter,
+        }
+    }
+
+    /// Whether the team could be told apart from everything else that shows
+    /// up on a board or in a position
+    fn is_valid(&self) -> bool {
+        !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            && self.emoji.len() <= MAX_EMOJI_LENGTH
+            && self.emoji.graphemes(true).count() == 1
+            && !self.emoji.starts_with([WALL, EMPTY])
+            && !self
+                .emoji
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+            && self.letter.is_ascii_lowercase()
+    }
+
+    fn clashes_with(&self, other: &Self) -> bool {
+        self.name == other.name || self.emoji == other.emoji || self.letter == other.letter
+    }
+}
+
+/// Teams in turn order. Games with `n` teams are played by the first `n` of
+/// them. Every game keeps the roster as it was when the game started, so its
+/// pieces go on meaning the same teams whatever happens to the registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Roster(Arc<Vec<Team>>);
+
+impl Default for Roster {
+    fn default() -> Self {
+        Self(Arc::new(vec![
+            Team::new("cookie", "🍪", 'c'),
+            Team::new("milk", "🥛", 'm'),
+            Team::new("carrot", "🥕", 'r'),
+        ]))
+    }
+}
+
+impl Roster {
+    pub(super) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn find(&self, matches: impl Fn(&Team) -> bool) -> Option<Piece> {
+        self.0.iter().position(matches).map(Piece)
+    }
+
+    pub(super) fn find_team(&self, name: &str) -> Option<Piece> {
+        self.find(|t| t.name == name)
+    }
+
+    pub(super) fn find_letter(&self, letter: char) -> Option<Piece> {
+        self.find(|t| t.letter == letter)
+    }
+
+    pub(super) fn find_emoji(&self, emoji: &str) -> Option<Piece> {
+        self.find(|t| t.emoji == emoji)
+    }
+
+    /// Name of the piece's team, as used in routes
+    pub(super) fn name(&self, piece: Piece) -> &str {
+        &self.0[piece.0].name
+    }
+
+    pub(super) fn letter(&self, piece: Piece) -> char {
+        self.0[piece.0].letter
+    }
+
+    pub(super) fn emoji(&self, piece: Piece) -> &str {
+        &self.0[piece.0].emoji
+    }
+}
+
+/// Every team there is, which new games take their rosters from
+pub type SharedTeams = Data<RwLock<Roster>>;
+
+pub fn new_shared_teams() -> SharedTeams {
+    Data::new(RwLock::new(Roster::default()))
+}
+
+impl Piece {
+    pub(super) const COOKIE: Self = Self(0);
+    pub(super) const MILK: Self = Self(1);
+
+    /// The team taking the turn after this one, in a game between `teams`
+    /// teams
+    pub(super) fn next(self, teams: usize) -> Self {
+        Self((self.0 + 1) % teams)
+    }
+
+    /// A piece from any of the first `teams` teams. Two teams get a coin
+    /// flip, which is how boards were always generated, so the shared
+    /// generator keeps dealing the same boards after a reset.
+    pub(super) fn random(teams: usize, rng: &mut StdRng) -> Self {
+        if teams == 2 {
+            if rng.gen() {
+                Self::COOKIE
+            } else {
+                Self::MILK
+            }
+        } else {
+            Self(rng.gen_range(0..teams))
+        }
+    }
+}
+
+#[get("/teams")]
+async fn list_teams(teams: SharedTeams) -> HttpResponse {
+    HttpResponse::Ok().json(&*teams.read().await.0)
+}
+
+#[post("/teams")]
+async fn register_team(teams: SharedTeams, team: Json<Team>) -> HttpResponse {
+    let team = team.into_inner();
+    if !team.is_valid() {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut teams = teams.write().await;
+    if teams.len() >= MAX_TEAMS || teams.0.iter().any(|t| t.clashes_with(&team)) {
+        return HttpResponse::Conflict().finish();
+    }
+    Arc::make_mut(&mut teams.0).push(team.clone());
+
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, "/12/teams"))
+        .json(team)
+}
+
+/// Take a team out of the registry, so that new games can't be played by it.
+/// Games that have already started keep it.
+#[delete("/teams/{name}")]
+async fn remove_team(teams: SharedTeams, name: Path<String>) -> HttpResponse {
+    let mut teams = teams.write().await;
+    let Some(piece) = teams.find_team(&name) else {
+        return HttpResponse::NotFound().finish();
+    };
+    if piece.0 < BUILT_IN_TEAMS {
+        return HttpResponse::Conflict().finish();
+    }
+    Arc::make_mut(&mut teams.0).remove(piece.0);
+
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pieces_round_trip_through_the_roster() {
+        let roster = Roster::default();
+        for (team, emoji, letter) in [
+            ("cookie", "🍪", 'c'),
+            ("milk", "🥛", 'm'),
+            ("carrot", "🥕", 'r'),
+        ] {
+            let piece = roster.find_team(team).unwrap();
+            assert_eq!(Some(piece), roster.find_emoji(emoji));
+            assert_eq!(Some(piece), roster.find_letter(letter));
+            assert_eq!(team, roster.name(piece));
+            assert_eq!(emoji, roster.emoji(piece));
+        }
+
+        assert_eq!(Piece::MILK, Piece::COOKIE.next(2));
+        assert_eq!(Piece::COOKIE, Piece::MILK.next(2));
+        assert_eq!(roster.find_team("carrot"), Some(Piece::MILK.next(3)));
+    }
+
+    #[test]
+    fn emoji_are_one_character_however_many_codepoints() {
+        for emoji in ["🐧", "🇳🇱", "👩‍👩‍👧", "❄️"] {
+            assert!(Team::new("team", emoji, 't').is_valid(), "{emoji}");
+        }
+        for emoji in ["", "🐧🐧", "ab", " ", "⬜", "⬛️"] {
+            assert!(!Team::new("team", emoji, 't').is_valid(), "{emoji}");
+        }
+    }
+}
@@ -0,0 +1,756 @@
+//! Tournaments between players of the cookie/milk game, with a game on the
+//! server for every match
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{get, post, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::team::{Roster, SharedTeams};
+use super::{
+    bearer_token, BoardConfig, Game, GameState, Games, Piece, SeatKey, SharedGames, SharedSeatKey,
+};
+
+/// How long a tournament that isn't over can go without anything happening
+/// before it's given up on. Finished ones go once they've been idle as long
+/// as a game can be.
+const MAX_IDLE: Duration = Duration::from_hours(7 * 24);
+
+/// How long a player can get seats at their matches for after entering
+const ENTRANT_LIFETIME: Duration = MAX_IDLE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Bracket {
+    /// Everyone plays everyone else once
+    RoundRobin,
+    /// Winners go through to the next round until there's only one left
+    SingleElimination,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum MatchResult {
+    Winner(String),
+    Draw,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Match {
+    round: usize,
+    cookie: String,
+    /// Nobody, if the cookie player has a bye
+    milk: Option<String>,
+    game: Option<Uuid>,
+    result: Option<MatchResult>,
+}
+
+impl Match {
+    fn new(
+        round: usize,
+        cookie: String,
+        milk: Option<String>,
+        games: &mut Games,
+        board: &Game,
+        key: &SeatKey,
+    ) -> Self {
+        match milk {
+            Some(milk) => Self {
+                round,
+                game: Some(match_game(games, board, &cookie, &milk, key)),
+                cookie,
+                milk: Some(milk),
+                result: None,
+            },
+            None => Self {
+                round,
+                result: Some(MatchResult::Winner(cookie.clone())),
+                cookie,
+                milk: None,
+                game: None,
+            },
+        }
+    }
+
+    fn player(&self, piece: Piece) -> Option<&String> {
+        match piece {
+            Piece::COOKIE => Some(&self.cookie),
+            Piece::MILK => self.milk.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Which team `player` plays for in the match, if they're in it
+    fn team_of(&self, player: &str) -> Option<Piece> {
+        [Piece::COOKIE, Piece::MILK]
+            .into_iter()
+            .find(|&piece| self.player(piece).is_some_and(|p| p == player))
+    }
+}
+
+/// A game for a match on a copy of the empty `board`, with both players
+/// already sitting at it so that nobody else can play in their place. They
+/// get their seat tokens through the tournament.
+fn match_game(games: &mut Games, board: &Game, cookie: &str, milk: &str, key: &SeatKey) -> Uuid {
+    let id = games.create_locked(board.clone(), ENTRANT_LIFETIME);
+    let session = games
+        .get_mut(&id)
+        .expect("the game should have just been created");
+    session.seat(&id, Piece::COOKIE, cookie.to_string(), key);
+    session.seat(&id, Piece::MILK, milk.to_string(), key);
+    id
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Standing {
+    player: String,
+    played: usize,
+    wins: usize,
+    draws: usize,
+    losses: usize,
+    points: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Tournament {
+    name: String,
+    bracket: Bracket,
+    board: BoardConfig,
+    /// The teams as they were when the tournament was created, which every
+    /// match is played with
+    #[serde(skip)]
+    roster: Roster,
+    players: Vec<String>,
+    started: bool,
+    matches: Vec<Match>,
+    #[serde(skip)]
+    last_active: Instant,
+}
+
+impl Tournament {
+    fn new(name: String, bracket: Bracket, board: BoardConfig, roster: Roster) -> Self {
+        Self {
+            name,
+            bracket,
+            board,
+            roster,
+            players: Vec::new(),
+            started: false,
+            matches: Vec::new(),
+            last_active: Instant::now(),
+        }
+    }
+
+    /// The board every match starts from
+    fn empty_board(&self) -> Game {
+        Game::with_config(self.board, self.roster.clone())
+    }
+
+    /// Generate the (first round of) matches, with a game for each
+    fn start(&mut self, games: &mut Games, key: &SeatKey) {
+        let board = self.empty_board();
+        let pairings = match self.bracket {
+            Bracket::RoundRobin => round_robin(&self.players),
+            Bracket::SingleElimination => pair_up(&self.players, 1),
+        };
+        self.matches = pairings
+            .into_iter()
+            .map(|(round, cookie, milk)| Match::new(round, cookie, milk, games, &board, key))
+            .collect();
+        self.started = true;
+        self.last_active = Instant::now();
+        self.advance(games, key);
+    }
+
+    /// Pick up the results of any matches that have finished since we last
+    /// looked. Games that are done with get released to expire.
+    fn update(&mut self, games: &mut Games, key: &SeatKey) {
+        self.last_active = Instant::now();
+        let board = self.empty_board();
+        for m in self.matches.iter_mut().filter(|m| m.result.is_none()) {
+            let Some(id) = m.game else {
+                continue;
+            };
+            let Some(state) = games.get(&id).map(Game::get_state) else {
+                continue;
+            };
+            match state {
+                GameState::Winner(piece) => {
+                    m.result = m.player(piece).cloned().map(MatchResult::Winner);
+                }
+                // Someone has to go through, so play it again
+                GameState::Draw if self.bracket == Bracket::SingleElimination => {
+                    let milk = m.milk.as_deref().unwrap_or_default();
+                    m.game = Some(match_game(games, &board, &m.cookie, milk, key));
+                }
+                GameState::Draw => m.result = Some(MatchResult::Draw),
+                GameState::NotEnded => continue,
+            }
+            games.release(&id);
+        }
+        self.advance(games, key);
+    }
+
+    /// Once a round of an elimination bracket is over, send the winners
+    /// through to the next one
+    fn advance(&mut self, games: &mut Games, key: &SeatKey) {
+        if self.bracket != Bracket::SingleElimination {
+            return;
+        }
+        let Some(round) = self.matches.last().map(|m| m.round) else {
+            return;
+        };
+
+        let mut winners = Vec::new();
+        for m in self.matches.iter().filter(|m| m.round == round) {
+            match &m.result {
+                Some(MatchResult::Winner(winner)) => winners.push(winner.clone()),
+                _ => return,
+            }
+        }
+        if winners.len() < 2 {
+            return;
+        }
+
+        let board = self.empty_board();
+        for (round, cookie, milk) in pair_up(&winners, round + 1) {
+            self.matches
+                .push(Match::new(round, cookie, milk, games, &board, key));
+        }
+        // A round of nothing but byes finishes straight away
+        self.advance(games, key);
+    }
+
+    fn finished(&self) -> bool {
+        self.started && self.matches.iter().all(|m| m.result.is_some())
+    }
+
+    /// Whether the tournament can be removed as of `now`
+    fn expired(&self, now: Instant) -> bool {
+        let max_idle = if self.finished() {
+            super::MAX_IDLE
+        } else {
+            MAX_IDLE
+        };
+        now.saturating_duration_since(self.last_active) >= max_idle
+    }
+
+    /// Players by points, then by name
+    fn standings(&self) -> Vec<Standing> {
+        let mut standings: HashMap<&str, Standing> = self
+            .players
+            .iter()
+            .map(|player| {
+                let standing = Standing {
+                    player: player.clone(),
+                    ..Standing::default()
+                };
+                (player.as_str(), standing)
+            })
+            .collect();
+
+        for m in &self.matches {
+            let (Some(milk), Some(result)) = (&m.milk, &m.result) else {
+                continue;
+            };
+            for player in [&m.cookie, milk] {
+                let Some(standing) = standings.get_mut(player.as_str()) else {
+                    continue;
+                };
+                standing.played += 1;
+                match result {
+                    MatchResult::Winner(winner) if winner == player => {
+                        standing.wins += 1;
+                        standing.points += 2;
+                    }
+                    MatchResult::Winner(_) => standing.losses += 1,
+                    MatchResult::Draw => {
+                        standing.draws += 1;
+                        standing.points += 1;
+                    }
+                }
+            }
+        }
+
+        let mut standings: Vec<_> = standings.into_values().collect();
+        standings.sort_by(|a, b| b.points.cmp(&a.points).then(a.player.cmp(&b.player)));
+        standings
+    }
+
+    /// Whoever won the tournament, once it's over and if there's a clear
+    /// winner
+    fn champion(&self, standings: &[Standing]) -> Option<String> {
+        if !self.finished() {
+            return None;
+        }
+        match self.bracket {
+            Bracket::SingleElimination => match &self.matches.last()?.result {
+                Some(MatchResult::Winner(winner)) => Some(winner.clone()),
+                _ => None,
+            },
+            Bracket::RoundRobin => match standings {
+                [first, second, ..] if first.points == second.points => None,
+                [first, ..] => Some(first.player.clone()),
+                [] => None,
+            },
+        }
+    }
+}
+
+/// Every pairing of players, in rounds where nobody plays twice. One player
+/// stays put while the rest rotate around them; with an odd number of
+/// players, whoever would face the empty seat sits the round out.
+fn round_robin(players: &[String]) -> Vec<(usize, String, Option<String>)> {
+    let mut seats: Vec<Option<&String>> = players.iter().map(Some).collect();
+    if seats.len() % 2 == 1 {
+        seats.push(None);
+    }
+
+    let n = seats.len();
+    let mut pairings = Vec::new();
+    for round in 1..n {
+        for i in 0..n / 2 {
+            if let (Some(cookie), Some(milk)) = (seats[i], seats[n - 1 - i]) {
+                pairings.push((round, cookie.clone(), Some(milk.clone())));
+            }
+        }
+        seats[1..].rotate_right(1);
+    }
+    pairings
+}
+
+/// Players paired off in order, with the last one getting a bye if there's
+/// an odd number of them
+fn pair_up(players: &[String], round: usize) -> Vec<(usize, String, Option<String>)> {
+    players
+        .chunks(2)
+        .map(|pair| (round, pair[0].clone(), pair.get(1).cloned()))
+        .collect()
+}
+
+pub struct Tournaments {
+    tournaments: HashMap<Uuid, Tournament>,
+}
+
+impl Tournaments {
+    /// Remove the tournaments that are over or have been given up on as of
+    /// `now`, letting their games expire too
+    fn expire_idle(&mut self, games: &mut Games, now: Instant) {
+        self.tournaments.retain(|_, tournament| {
+            if !tournament.expired(now) {
+                return true;
+            }
+            for id in tournament.matches.iter().filter_map(|m| m.game) {
+                games.release(&id);
+            }
+            false
+        });
+    }
+}
+
+pub type SharedTournaments = Data<RwLock<Tournaments>>;
+
+pub fn new_shared_tournaments() -> SharedTournaments {
+    Data::new(RwLock::new(Tournaments {
+        tournaments: HashMap::new(),
+    }))
+}
+
+/// Periodically remove tournaments that are over or that nobody is playing
+pub fn expire_idle_tournaments(tournaments: SharedTournaments, games: SharedGames) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_mins(1));
+        loop {
+            interval.tick().await;
+            let mut tournaments = tournaments.write().await;
+            tournaments.expire_idle(&mut *games.write().await, Instant::now());
+        }
+    });
+}
+
+/// Pick up the result of a game, if it's for a match in a tournament
+pub(super) async fn pick_up_result(
+    tournaments: &SharedTournaments,
+    games: &SharedGames,
+    key: &SeatKey,
+    game: &Uuid,
+) {
+    let in_match =
+        |tournament: &Tournament| tournament.matches.iter().any(|m| m.game == Some(*game));
+    let Some(id) = tournaments
+        .read()
+        .await
+        .tournaments
+        .iter()
+        .find_map(|(id, tournament)| in_match(tournament).then_some(*id))
+    else {
+        return;
+    };
+
+    if let Some(tournament) = tournaments.write().await.tournaments.get_mut(&id) {
+        tournament.update(&mut *games.write().await, key);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TournamentView<'a> {
+    id: Uuid,
+    #[serde(flatten)]
+    tournament: &'a Tournament,
+    standings: Vec<Standing>,
+    champion: Option<String>,
+}
+
+impl<'a> TournamentView<'a> {
+    fn new(id: Uuid, tournament: &'a Tournament) -> Self {
+        let standings = tournament.standings();
+        let champion = tournament.champion(&standings);
+        Self {
+            id,
+            tournament,
+            standings,
+            champion,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TournamentSummary<'a> {
+    id: Uuid,
+    name: &'a str,
+    bracket: Bracket,
+    players: usize,
+    started: bool,
+    finished: bool,
+}
+
+#[get("/tournaments")]
+async fn list_tournaments(tournaments: SharedTournaments) -> HttpResponse {
+    let tournaments = tournaments.read().await;
+    let summaries: Vec<_> = tournaments
+        .tournaments
+        .iter()
+        .map(|(id, tournament)| TournamentSummary {
+            id: *id,
+            name: &tournament.name,
+            bracket: tournament.bracket,
+            players: tournament.players.len(),
+            started: tournament.started,
+            finished: tournament.finished(),
+        })
+        .collect();
+    HttpResponse::Ok().json(summaries)
+}
+
+#[derive(Debug, Deserialize)]
+struct NewTournament {
+    name: String,
+    bracket: Bracket,
+    #[serde(default)]
+    board: BoardConfig,
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedTournament {
+    id: Uuid,
+}
+
+#[post("/tournaments")]
+async fn create_tournament(
+    tournaments: SharedTournaments,
+    teams: SharedTeams,
+    data: Json<NewTournament>,
+) -> HttpResponse {
+    let NewTournament {
+        name,
+        bracket,
+        board,
+    } = data.into_inner();
+    // Matches are always one player against another
+    let roster = teams.read().await.clone();
+    if !board.is_valid(&roster) || board.teams != 2 {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let id = Uuid::new_v4();
+    tournaments
+        .write()
+        .await
+        .tournaments
+        .insert(id, Tournament::new(name, bracket, board, roster));
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/12/tournaments/{id}")))
+        .json(CreatedTournament { id })
+}
+
+/// Results are picked up as matches finish, so looking doesn't change anything
+#[get("/tournaments/{id}")]
+async fn show_tournament(id: Path<Uuid>, tournaments: SharedTournaments) -> HttpResponse {
+    match tournaments.read().await.tournaments.get(&id) {
+        Some(tournament) => HttpResponse::Ok().json(TournamentView::new(*id, tournament)),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NewPlayer {
+    name: String,
+}
+
+/// Claims of the token a player gets when they enter a tournament, which they
+/// get seats at their matches with
+#[derive(Debug, Serialize, Deserialize)]
+struct Entrant {
+    tournament: Uuid,
+    player: String,
+}
+
+#[derive(Debug, Serialize)]
+struct EnteredTournament<'a> {
+    token: String,
+    #[serde(flatten)]
+    tournament: TournamentView<'a>,
+}
+
+#[post("/tournaments/{id}/players")]
+async fn add_player(
+    id: Path<Uuid>,
+    tournaments: SharedTournaments,
+    key: SharedSeatKey,
+    data: Json<NewPlayer>,
+) -> HttpResponse {
+    let name = data.into_inner().name;
+    if name.trim().is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut tournaments = tournaments.write().await;
+    let Some(tournament) = tournaments.tournaments.get_mut(&id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    if tournament.started || tournament.players.contains(&name) {
+        return HttpResponse::Conflict().finish();
+    }
+
+    let entrant = Entrant {
+        tournament: *id,
+        player: name.clone(),
+    };
+    tournament.players.push(name);
+    tournament.last_active = Instant::now();
+    HttpResponse::Ok().json(EnteredTournament {
+        token: key.sign(entrant, ENTRANT_LIFETIME),
+        tournament: TournamentView::new(*id, tournament),
+    })
+}
+
+#[post("/tournaments/{id}/start")]
+async fn start_tournament(
+    id: Path<Uuid>,
+    tournaments: SharedTournaments,
+    games: SharedGames,
+    key: SharedSeatKey,
+) -> HttpResponse {
+    let mut tournaments = tournaments.write().await;
+    let Some(tournament) = tournaments.tournaments.get_mut(&id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    if tournament.started || tournament.players.len() < 2 {
+        return HttpResponse::Conflict().finish();
+    }
+
+    tournament.start(&mut *games.write().await, &key);
+    HttpResponse::Ok().json(TournamentView::new(*id, tournament))
+}
+
+#[derive(Debug, Serialize)]
+struct MatchSeat {
+    game: Uuid,
+    /// Name of the team the player plays for
+    team: String,
+    token: String,
+}
+
+/// Seats at the matches a player has to play, with new tokens to play them
+/// with. Any tokens handed out for those seats before stop working.
+#[post("/tournaments/{id}/seats")]
+async fn take_seats(
+    id: Path<Uuid>,
+    tournaments: SharedTournaments,
+    games: SharedGames,
+    key: SharedSeatKey,
+    request: HttpRequest,
+) -> HttpResponse {
+    let Some(entrant) = bearer_token(&request).and_then(|t| key.verify::<Entrant>(t)) else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    if entrant.tournament != *id {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let mut tournaments = tournaments.write().await;
+    let Some(tournament) = tournaments.tournaments.get_mut(&id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mut games = games.write().await;
+    tournament.update(&mut games, &key);
+
+    let seats: Vec<_> = tournament
+        .matches
+        .iter()
+        .filter(|m| m.result.is_none())
+        .filter_map(|m| {
+            let game = m.game?;
+            let team = m.team_of(&entrant.player)?;
+            let session = games.get_mut(&game)?;
+            let token = session.seat(&game, team, entrant.player.clone(), &key);
+            let team = session.game.roster.name(team).to_string();
+            Some(MatchSeat { game, team, token })
+        })
+        .collect();
+    HttpResponse::Ok().json(seats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use jwt_simple::prelude::HS256Key;
+
+    use super::*;
+
+    fn players(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("player{i}")).collect()
+    }
+
+    #[test]
+    fn round_robin_pairs_everyone_once() {
+        for n in [2, 5, 6] {
+            let pairings = round_robin(&players(n));
+
+            let pairs: HashSet<_> = pairings
+                .iter()
+                .map(|(_, cookie, milk)| {
+                    let milk = milk.clone().unwrap();
+                    (cookie.clone().min(milk.clone()), cookie.clone().max(milk))
+                })
+                .collect();
+            assert_eq!(n * (n - 1) / 2, pairings.len());
+            assert_eq!(pairings.len(), pairs.len());
+
+            // Nobody plays twice in a round
+            for round in 1..n {
+                let mut seen = HashSet::new();
+                for (_, cookie, milk) in pairings.iter().filter(|(r, _, _)| *r == round) {
+                    assert!(seen.insert(cookie));
+                    assert!(seen.insert(milk.as_ref().unwrap()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn elimination_advances_winners_to_a_champion() {
+        let mut games = Games::new();
+        let mut tournament = Tournament::new(
+            "office".into(),
+            Bracket::SingleElimination,
+            BoardConfig::default(),
+            Roster::default(),
+        );
+        tournament.players = players(3);
+        let key = SeatKey(HS256Key::generate());
+        tournament.start(&mut games, &key);
+
+        // player1 plays player2, player3 has a bye
+        assert_eq!(2, tournament.matches.len());
+        assert_eq!(
+            Some(MatchResult::Winner("player3".into())),
+            tournament.matches[1].result
+        );
+
+        // Milk wins every game
+        let win_as_milk = |games: &mut Games, game: Uuid| {
+            let game = &mut games.get_mut(&game).unwrap().game;
+            for (column, piece) in [0, 1, 0, 1, 2, 1, 3, 1]
+                .into_iter()
+                .zip([Piece::COOKIE, Piece::MILK].into_iter().cycle())
+            {
+                game.place(piece, column);
+            }
+        };
+        win_as_milk(&mut games, tournament.matches[0].game.unwrap());
+        tournament.update(&mut games, &key);
+
+        assert_eq!(3, tournament.matches.len());
+        let last = &tournament.matches[2];
+        assert_eq!(
+            ("player2", Some("player3")),
+            (last.cookie.as_str(), last.milk.as_deref())
+        );
+
+        win_as_milk(&mut games, last.game.unwrap());
+        tournament.update(&mut games, &key);
+
+        let standings = tournament.standings();
+        assert_eq!(Some("player3".into()), tournament.champion(&standings));
+        let losses: Vec<_> = standings
+            .iter()
+            .map(|s| (s.player.as_str(), s.losses))
+            .collect();
+        assert!(losses.contains(&("player1", 1)));
+        assert!(losses.contains(&("player2", 1)));
+        assert!(losses.contains(&("player3", 0)));
+    }
+
+    #[test]
+    fn matches_are_reserved_and_let_go_when_done() {
+        let mut games = Games::new();
+        let mut tournaments = Tournaments {
+            tournaments: HashMap::new(),
+        };
+        let mut tournament = Tournament::new(
+            "office".into(),
+            Bracket::RoundRobin,
+            BoardConfig::default(),
+            Roster::default(),
+        );
+        tournament.players = players(2);
+        let key = SeatKey(HS256Key::generate());
+        tournament.start(&mut games, &key);
+
+        // The players have the seats, so nobody else can take them
+        let game = tournament.matches[0].game.unwrap();
+        let seats = &games.get_session(&game).unwrap().seats;
+        assert_eq!("player1", seats[&Piece::COOKIE].player);
+        assert_eq!("player2", seats[&Piece::MILK].player);
+
+        // A drawn game is over in a round robin
+        let board = &mut games.get_mut(&game).unwrap().game;
+        for (column, piece) in [0, 0, 0, 0, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 1]
+            .into_iter()
+            .zip([Piece::COOKIE, Piece::MILK].into_iter().cycle())
+        {
+            board.place(piece, column);
+        }
+        tournament.update(&mut games, &key);
+        assert_eq!(Some(MatchResult::Draw), tournament.matches[0].result);
+        assert!(tournament.finished());
+
+        let id = Uuid::new_v4();
+        let finished_at = tournament.last_active;
+        tournaments.tournaments.insert(id, tournament);
+        tournaments.expire_idle(&mut games, finished_at);
+        assert!(tournaments.tournaments.contains_key(&id));
+
+        tournaments.expire_idle(&mut games, finished_at + super::super::MAX_IDLE);
+        assert!(tournaments.tournaments.is_empty());
+        games.expire_idle(Instant::now() + super::super::MAX_IDLE);
+        assert!(games.get(&game).is_none());
+    }
+}
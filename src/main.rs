@@ -1,15 +1,21 @@
+use std::convert::Infallible;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::Mutex;
 
 use actix_web::cookie::Cookie;
 use actix_web::http::header;
+use actix_web::web::Bytes;
 use actix_web::web::{Data, Header, Json, Query, ServiceConfig};
 use actix_web::{get, post, Either, HttpRequest, HttpResponse};
 use cargo_toml::ContentType;
+use chrono::TimeDelta;
 use jwt_simple::{prelude::*, JWTError};
 use serde::Deserialize;
 use serde_json::Value;
 use shuttle_actix_web::ShuttleActixWeb;
+use shuttle_runtime::SecretStore;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::StreamExt;
 
 mod bucket;
 mod cargo_toml;
@@ -108,7 +114,7 @@ async fn day2part3key(params: Query<V6KeyQueryParams>) -> String {
         .octets()
         .iter()
         .enumerate()
-        .map(|(i, o)| (o ^ params.from.octets()[i]))
+        .map(|(i, o)| o ^ params.from.octets()[i])
         .collect();
 
     let parts: [u8; 16] = parts.try_into().unwrap();
@@ -174,6 +180,18 @@ async fn day9refill(bucket: Data<Mutex<Bucket>>) -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
+#[get("/9/level")]
+async fn day9level(bucket: Data<Mutex<Bucket>>) -> HttpResponse {
+    let level = bucket.lock().unwrap().subscribe();
+    let events = WatchStream::new(level)
+        .map(|level| Ok::<_, Infallible>(Bytes::from(format!("data: {level}\n\n"))));
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        .streaming(events)
+}
+
 type JWTKey = Data<HS256Key>;
 
 #[post("/16/wrap")]
@@ -260,21 +278,50 @@ async fn day16part2(jwt: String) -> Either<HttpResponse, Json<Value>> {
 #[shuttle_runtime::main]
 async fn main(
     #[shuttle_shared_db::Postgres] pool: sqlx::PgPool,
+    #[shuttle_runtime::Secrets] secrets: SecretStore,
 ) -> ShuttleActixWeb<impl FnOnce(&mut ServiceConfig) + Send + Clone + 'static> {
     let bucket = Data::new(Mutex::new(Bucket::new())).clone();
-    let game = game::new_shared_game().clone();
+    bucket::watch_regeneration(bucket.clone());
+    let games = game::new_shared_games().clone();
+    game::expire_idle_games(games.clone());
+    let tournaments = game::new_shared_tournaments().clone();
+    game::expire_idle_tournaments(tournaments.clone(), games.clone());
     let rng = game::new_shared_rng().clone();
+    let teams = game::new_shared_teams().clone();
+    let seat_key = game::new_seat_key().clone();
     let jwt_key = Data::new(HS256Key::generate()).clone();
-    let db = quote_book::shared_db_pool(pool).await.clone();
-    let page_cache = quote_book::shared_page_cache().clone();
+    // A fixed secret keeps quote book tokens valid across restarts
+    let token_key = quote_book::shared_token_key(secrets.get("JWT_SECRET")).clone();
+    let db = quote_book::shared_db_pool(pool)
+        .await
+        .map_err(|err| shuttle_runtime::Error::Database(err.to_string()))?
+        .clone();
+    // A retention that can't be right is better caught before anything gets
+    // purged by it
+    let trash_retention = secrets
+        .get("QUOTE_TRASH_RETENTION_DAYS")
+        .map_or(Some(30), |days| days.parse().ok())
+        .filter(|&days| days > 0)
+        .and_then(TimeDelta::try_days)
+        .ok_or_else(|| {
+            shuttle_runtime::Error::Custom(shuttle_runtime::CustomError::msg(
+                "QUOTE_TRASH_RETENTION_DAYS should be a positive number of days",
+            ))
+        })?;
+    quote_book::purge_trash(db.clone(), trash_retention);
+    let admin_key = quote_book::shared_admin_key(secrets.get("QUOTE_BOOK_ADMIN_KEY")).clone();
 
     let config = move |cfg: &mut ServiceConfig| {
         cfg.app_data(bucket)
-            .app_data(game)
+            .app_data(games)
+            .app_data(tournaments)
             .app_data(rng)
+            .app_data(teams)
             .app_data(jwt_key)
+            .app_data(token_key)
+            .app_data(seat_key)
             .app_data(db)
-            .app_data(page_cache)
+            .app_data(admin_key)
             .service(hello_bird)
             .service(rick_roll)
             .service(day2part1)
@@ -284,6 +331,7 @@ async fn main(
             .service(day5)
             .service(day9)
             .service(day9refill)
+            .service(day9level)
             .service(game::scope())
             .service(day16part1wrap)
             .service(day16part1unwrap)
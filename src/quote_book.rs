@@ -1,27 +1,37 @@
-use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
 use std::str::FromStr;
 
-use actix_web::web::{Data, Json, Path, Query};
-use actix_web::{delete, get, post, put, Either, HttpResponse, Scope};
-use chrono::{DateTime, Utc};
-use rand::rngs::StdRng;
-use rand::Rng;
+use actix_web::http::header::{self, EntityTag, Header as _};
+use actix_web::web::{Data, Json, JsonConfig, Path, PathConfig, Query, QueryConfig};
+use actix_web::{delete, get, post, put, HttpRequest, HttpResponse, Scope};
+use chrono::{DateTime, TimeDelta, Utc};
+use jwt_simple::prelude::{Claims, Duration, HS256Key, JWTClaims, MACLike, VerificationOptions};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
-use tokio::sync::Mutex;
+use sqlx::{FromRow, PgConnection, PgPool, Postgres, QueryBuilder};
+use unicode_normalization::UnicodeNormalization;
 use uuid::Uuid;
 
-use crate::game::SharedRng;
+use crate::game::bearer_token;
+
+mod collections;
+mod error;
+mod featured;
+mod tags;
+mod transfer;
+
+use error::QuoteBookError;
 
 type SharedDBPool = Data<PgPool>;
 
-pub async fn shared_db_pool(pool: PgPool) -> SharedDBPool {
-    sqlx::migrate!()
-        .run(&pool)
-        .await
-        .expect("Migration was executed successfully");
+pub async fn shared_db_pool(pool: PgPool) -> Result<SharedDBPool, sqlx::migrate::MigrateError> {
+    sqlx::migrate!().run(&pool).await?;
 
-    Data::new(pool)
+    Ok(Data::new(pool))
+}
+
+fn parse_id(id: &str) -> Result<Uuid, QuoteBookError> {
+    Uuid::from_str(id).map_err(|_| QuoteBookError::BadRequest("not a valid ID"))
 }
 
 #[derive(Debug, Serialize, FromRow)]
@@ -32,218 +42,1216 @@ struct Quote {
     quote: String,
     created_at: DateTime<Utc>,
     version: i32,
+    /// When the quote was put in the trash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>,
 }
 
 impl Quote {
     async fn find(pool: &PgPool, id: &Uuid) -> Result<Self, sqlx::Error> {
-        sqlx::query_as::<_, Self>("SELECT * FROM quotes WHERE id = $1")
+        sqlx::query_as::<_, Self>("SELECT * FROM quotes WHERE id = $1 AND deleted_at IS NULL")
             .bind(id)
             .fetch_one(pool)
             .await
     }
+
+    async fn create(pool: &PgPool, author: &str, quote: &str) -> Result<Self, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let created = sqlx::query_as::<_, Self>(
+            "INSERT INTO quotes (id, author, quote) VALUES ($1, $2, $3) RETURNING *",
+        )
+        .bind(Uuid::new_v4())
+        .bind(author)
+        .bind(quote)
+        .fetch_one(&mut *tx)
+        .await?;
+        created.record(&mut tx, Action::Draft).await?;
+        tx.commit().await?;
+        Ok(created)
+    }
+
+    /// Replace the text of a quote, as a new version of it. If the quote isn't
+    /// at one of the `expected` versions, it's left alone and not found.
+    async fn update(
+        pool: &PgPool,
+        id: &Uuid,
+        author: &str,
+        quote: &str,
+        expected: Option<&[i32]>,
+        action: Action,
+    ) -> Result<Self, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let updated = sqlx::query_as::<_, Self>(
+            "UPDATE quotes SET author = $1, quote = $2, version = version + 1 \
+             WHERE id = $3 AND deleted_at IS NULL AND ($4::INT[] IS NULL OR version = ANY($4)) \
+             RETURNING *",
+        )
+        .bind(author)
+        .bind(quote)
+        .bind(id)
+        .bind(expected)
+        .fetch_one(&mut *tx)
+        .await?;
+        updated.record(&mut tx, action).await?;
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    /// Put a quote in the trash
+    async fn delete(
+        pool: &PgPool,
+        id: &Uuid,
+        expected: Option<&[i32]>,
+    ) -> Result<Self, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let deleted = sqlx::query_as::<_, Self>(
+            "UPDATE quotes SET deleted_at = CURRENT_TIMESTAMP \
+             WHERE id = $1 AND deleted_at IS NULL AND ($2::INT[] IS NULL OR version = ANY($2)) \
+             RETURNING *",
+        )
+        .bind(id)
+        .bind(expected)
+        .fetch_one(&mut *tx)
+        .await?;
+        deleted.record(&mut tx, Action::Remove).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// Take a quote back out of the trash
+    async fn restore(pool: &PgPool, id: &Uuid) -> Result<Self, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let restored = sqlx::query_as::<_, Self>(
+            "UPDATE quotes SET deleted_at = NULL \
+             WHERE id = $1 AND deleted_at IS NOT NULL RETURNING *",
+        )
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        restored.record(&mut tx, Action::Restore).await?;
+        tx.commit().await?;
+        Ok(restored)
+    }
+
+    fn etag(&self) -> header::ETag {
+        header::ETag(EntityTag::new_strong(self.version.to_string()))
+    }
+
+    /// Add the quote as it now stands to its history
+    async fn record(&self, conn: &mut PgConnection, action: Action) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO quote_revisions (id, quote_id, version, author, quote, action) \
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(self.id)
+        .bind(self.version)
+        .bind(&self.author)
+        .bind(&self.quote)
+        .bind(action.as_str())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct QuoteRequest {
+/// Something done to a quote that leaves a revision behind
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Draft,
+    Undo,
+    Revert,
+    Remove,
+    Restore,
+    Import,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Draft => "draft",
+            Self::Undo => "undo",
+            Self::Revert => "revert",
+            Self::Remove => "remove",
+            Self::Restore => "restore",
+            Self::Import => "import",
+        }
+    }
+}
+
+/// A quote as it was at some point in its history
+#[derive(Debug, Serialize, FromRow)]
+struct Revision {
+    version: i32,
     author: String,
+    #[allow(clippy::struct_field_names)]
     quote: String,
+    action: String,
+    revised_at: DateTime<Utc>,
 }
 
-#[post("reset")]
-async fn reset(pool: SharedDBPool) -> HttpResponse {
-    sqlx::query("TRUNCATE TABLE quotes")
-        .execute(&**pool)
-        .await
-        .expect("Couldn't truncate quotes table");
+/// What a token lets its holder do to the quote book. Each role can do
+/// everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Role {
+    Reader,
+    Editor,
+    Admin,
+}
 
-    HttpResponse::Ok().finish()
+/// Claims of the token handed out for a role
+#[derive(Debug, Serialize, Deserialize)]
+struct Grant {
+    role: Role,
 }
 
-#[get("cite/{id}")]
-async fn cite(id: Path<String>, pool: SharedDBPool) -> Either<HttpResponse, Json<Quote>> {
-    let Ok(id) = Uuid::from_str(&id) else {
-        return Either::Left(HttpResponse::BadRequest().finish());
-    };
-    let res = Quote::find(&pool, &id).await;
+/// Secret that has to be presented to be handed a token. Without one, no
+/// tokens are handed out.
+pub struct AdminKey(Option<String>);
 
-    match res {
-        Ok(quote) => Either::Right(Json(quote)),
-        Err(err) => {
-            dbg!(err);
-            Either::Left(HttpResponse::NotFound().finish())
+type SharedAdminKey = Data<AdminKey>;
+
+pub fn shared_admin_key(key: Option<String>) -> SharedAdminKey {
+    Data::new(AdminKey(key))
+}
+
+impl AdminKey {
+    /// Whether `presented` is the key, taking as long to tell whatever it is
+    fn matches(&self, presented: Option<&str>) -> bool {
+        match (&self.0, presented) {
+            (Some(key), Some(presented)) => {
+                constant_time_eq::constant_time_eq(key.as_bytes(), presented.as_bytes())
+            }
+            _ => false,
         }
     }
 }
 
-#[delete("remove/{id}")]
-async fn remove(id: Path<String>, pool: SharedDBPool) -> Either<HttpResponse, Json<Quote>> {
-    let Ok(id) = Uuid::from_str(&id) else {
-        return Either::Left(HttpResponse::BadRequest().finish());
+/// Audience of every token the quote book signs
+const TOKEN_AUDIENCE: &str = "quote-book";
+
+/// The key that the quote book signs grants and cursors with. It isn't used
+/// for anything else, and its tokens all name the quote book as their
+/// audience, so tokens signed elsewhere never pass for them.
+pub struct TokenKey(HS256Key);
+
+type SharedTokenKey = Data<TokenKey>;
+
+/// A key from `secret`, so that tokens stay valid across restarts, or a new
+/// one if there's no secret
+pub fn shared_token_key(secret: Option<String>) -> SharedTokenKey {
+    let key = secret.map_or_else(HS256Key::generate, |secret| {
+        HS256Key::from_bytes(secret.as_bytes())
+    });
+    Data::new(TokenKey(key))
+}
+
+impl TokenKey {
+    fn sign<T: Serialize + DeserializeOwned>(
+        &self,
+        claims: JWTClaims<T>,
+    ) -> Result<String, jwt_simple::Error> {
+        self.0.authenticate(claims.with_audience(TOKEN_AUDIENCE))
+    }
+
+    fn verify<T: Serialize + DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let options = VerificationOptions {
+            allowed_audiences: Some([TOKEN_AUDIENCE.to_string()].into()),
+            ..VerificationOptions::default()
+        };
+        let claims = self.0.verify_token::<T>(token, Some(options)).ok()?;
+        Some(claims.custom)
+    }
+}
+
+/// Check that the request carries a token for at least the given role
+fn authorize(request: &HttpRequest, key: &TokenKey, role: Role) -> Result<(), QuoteBookError> {
+    let Some(grant) = bearer_token(request).and_then(|t| key.verify::<Grant>(t)) else {
+        return Err(QuoteBookError::Unauthorized);
     };
-    let res = sqlx::query_as::<_, Quote>("DELETE FROM quotes WHERE id = $1 RETURNING *")
-        .bind(id)
-        .fetch_one(&**pool)
-        .await;
+    if grant.role < role {
+        return Err(QuoteBookError::Forbidden);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenRequest {
+    role: Role,
+    /// Who the token is for
+    subject: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct IssuedToken {
+    token: String,
+}
+
+#[post("/token")]
+async fn issue_token(
+    request: HttpRequest,
+    admin_key: SharedAdminKey,
+    key: SharedTokenKey,
+    form: Json<TokenRequest>,
+) -> Result<HttpResponse, QuoteBookError> {
+    if !admin_key.matches(bearer_token(&request)) {
+        return Err(QuoteBookError::Unauthorized);
+    }
+
+    let TokenRequest { role, subject } = form.into_inner();
+    let mut claims = Claims::with_custom_claims(Grant { role }, Duration::from_hours(12));
+    if let Some(subject) = subject {
+        claims = claims.with_subject(subject);
+    }
+    let token = key.sign(claims)?;
+
+    Ok(HttpResponse::Ok().json(IssuedToken { token }))
+}
+
+/// Versions of a quote the client is prepared to change, as sent in
+/// `If-Match`, or `None` if any version will do
+fn expected_versions(request: &HttpRequest) -> Result<Option<Vec<i32>>, QuoteBookError> {
+    match header::IfMatch::parse(request) {
+        Ok(header::IfMatch::Items(tags)) if !tags.is_empty() => Ok(Some(
+            tags.iter()
+                // Weak tags never match
+                .filter(|tag| !tag.weak)
+                .filter_map(|tag| tag.tag().parse().ok())
+                .collect(),
+        )),
+        Ok(header::IfMatch::Any) => Ok(None),
+        Ok(_) if !request.headers().contains_key(header::IF_MATCH) => Ok(None),
+        // Sent, but without a single tag that could be made sense of
+        _ => Err(QuoteBookError::BadRequest("If-Match has no versions in it")),
+    }
+}
 
+/// Respond with a quote that was just changed, or explain why it couldn't be
+async fn changed(
+    pool: &PgPool,
+    id: &Uuid,
+    res: Result<Quote, sqlx::Error>,
+) -> Result<HttpResponse, QuoteBookError> {
     match res {
-        Ok(quote) => Either::Right(Json(quote)),
-        Err(err) => {
-            dbg!(err);
-            Either::Left(HttpResponse::NotFound().finish())
+        Ok(quote) => Ok(HttpResponse::Ok().insert_header(quote.etag()).json(quote)),
+        // Either there's no such quote, or it isn't at the version the client
+        // expected any more
+        Err(sqlx::Error::RowNotFound) => {
+            Quote::find(pool, id).await?;
+            Err(QuoteBookError::PreconditionFailed)
         }
+        Err(err) => Err(err.into()),
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct QuoteRequest {
+    author: String,
+    quote: String,
+}
+
+const MAX_AUTHOR_LENGTH: usize = 100;
+const MAX_QUOTE_LENGTH: usize = 1000;
+
+/// What's wrong with one field of a quote
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct FieldError {
+    field: &'static str,
+    message: String,
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{} {}", self.field, self.message)
+    }
+}
+
+/// Trim and normalize a field, checking that what's left is reasonable.
+/// Line breaks all become `\n`, whichever system they were typed on. Quotes
+/// can run over several lines and be indented with tabs, like verse, but
+/// there's no other place for control characters.
+fn clean(
+    field: &'static str,
+    value: &str,
+    max_length: usize,
+    multiline: bool,
+) -> Result<String, FieldError> {
+    let error = |message: String| FieldError { field, message };
+
+    let value: String = value
+        .trim()
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .nfc()
+        .collect();
+    if value.is_empty() {
+        return Err(error("must not be empty".to_string()));
+    }
+    if value
+        .chars()
+        .any(|c| c.is_control() && !(multiline && matches!(c, '\n' | '\t')))
+    {
+        return Err(error("must not contain control characters".to_string()));
+    }
+    if value.chars().count() > max_length {
+        return Err(error(format!("must be at most {max_length} characters")));
+    }
+    Ok(value)
+}
+
+impl QuoteRequest {
+    /// The request with every field cleaned up, or every field that couldn't
+    /// be
+    fn validate(&self) -> Result<Self, Vec<FieldError>> {
+        let author = clean("author", &self.author, MAX_AUTHOR_LENGTH, false);
+        let quote = clean("quote", &self.quote, MAX_QUOTE_LENGTH, true);
+        match (author, quote) {
+            (Ok(author), Ok(quote)) => Ok(Self { author, quote }),
+            (author, quote) => Err(author.err().into_iter().chain(quote.err()).collect()),
+        }
+    }
+}
+
+#[post("reset")]
+async fn reset(
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Admin)?;
+
+    sqlx::query(
+        "TRUNCATE TABLE quotes, quote_revisions, quote_tags, tags, collection_quotes, collections, \
+         daily_quotes",
+    )
+    .execute(&**pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("cite/{id}")]
+async fn cite(id: Path<String>, pool: SharedDBPool) -> Result<HttpResponse, QuoteBookError> {
+    let id = parse_id(&id)?;
+    let quote = Quote::find(&pool, &id).await?;
+
+    Ok(HttpResponse::Ok().insert_header(quote.etag()).json(quote))
+}
+
+#[delete("remove/{id}")]
+async fn remove(
+    id: Path<String>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let id = parse_id(&id)?;
+    let expected = expected_versions(&request)?;
+
+    let res = Quote::delete(&pool, &id, expected.as_deref()).await;
+    changed(&pool, &id, res).await
+}
+
 #[put("undo/{id}")]
 async fn undo(
     id: Path<String>,
     pool: SharedDBPool,
     form: Json<QuoteRequest>,
-) -> Either<HttpResponse, Json<Quote>> {
-    let Ok(id) = Uuid::from_str(&id) else {
-        return Either::Left(HttpResponse::BadRequest().finish());
-    };
-    let Ok(mut quote) = Quote::find(&pool, &id).await else {
-        return Either::Left(HttpResponse::NotFound().finish());
-    };
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let id = parse_id(&id)?;
+    let expected = expected_versions(&request)?;
+    let form = form.validate().map_err(QuoteBookError::Invalid)?;
 
-    quote.author.clone_from(&form.author);
-    quote.quote.clone_from(&form.quote);
-    quote.version += 1;
+    let res = Quote::update(
+        &pool,
+        &id,
+        &form.author,
+        &form.quote,
+        expected.as_deref(),
+        Action::Undo,
+    )
+    .await;
+    changed(&pool, &id, res).await
+}
 
-    let res = sqlx::query("UPDATE quotes SET author = $1, quote = $2, version = $3 WHERE id = $4")
-        .bind(&quote.author)
-        .bind(&quote.quote)
-        .bind(quote.version)
-        .bind(quote.id)
-        .execute(&**pool)
-        .await;
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    /// Words to look for, with `"quoted phrases"`, `or` and `-excluded`
+    /// words like a web search
+    q: String,
+    author: Option<String>,
+    limit: Option<i64>,
+}
 
-    match res {
-        Ok(_) => Either::Right(Json(quote)),
-        Err(err) => {
-            dbg!(err);
-            Either::Left(HttpResponse::InternalServerError().finish())
+#[derive(Debug, Serialize, FromRow)]
+struct SearchResult {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    quote: Quote,
+    rank: f32,
+    /// HTML for the part of the quote that matched, with the text escaped and
+    /// matching words wrapped in `<mark>`
+    snippet: String,
+}
+
+/// Put around matching words by Postgres, to be swapped for `<mark>` once the
+/// rest of the snippet is escaped. Quotes can't contain control characters,
+/// so these never come from the quote itself.
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// Turn a headline from Postgres into HTML that's safe to show as it is
+fn highlight(headline: &str) -> String {
+    let mut html = String::with_capacity(headline.len());
+    for c in headline.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(c),
         }
     }
+    html
 }
 
-#[post("/draft")]
-async fn draft(pool: SharedDBPool, form: Json<QuoteRequest>) -> HttpResponse {
-    match sqlx::query_as::<_, Quote>(
-        "INSERT INTO quotes (id, author, quote) VALUES ($1, $2, $3) RETURNING *",
+#[get("search")]
+async fn search(
+    pool: SharedDBPool,
+    query: Query<SearchParams>,
+) -> Result<HttpResponse, QuoteBookError> {
+    const MAX_LIMIT: i64 = 50;
+
+    if query.q.trim().is_empty() {
+        return Err(QuoteBookError::BadRequest("there's nothing to search for"));
+    }
+    let limit = query.limit.unwrap_or(10).clamp(1, MAX_LIMIT);
+
+    let results = SearchResult::find(&pool, &query.q, query.author.as_deref(), limit).await?;
+    Ok(HttpResponse::Ok().json(results))
+}
+
+impl SearchResult {
+    /// Quotes matching the search, best match first
+    async fn find(
+        pool: &PgPool,
+        q: &str,
+        author: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let mut results = sqlx::query_as::<_, Self>(
+            "SELECT id, author, quote, created_at, version, deleted_at, \
+             ts_rank(search, query) AS rank, \
+             ts_headline('english', quote, query, $4) AS snippet \
+         FROM quotes, websearch_to_tsquery('english', $1) AS query \
+         WHERE deleted_at IS NULL AND search @@ query \
+             AND ($2::TEXT IS NULL OR lower(author) = lower($2)) \
+         ORDER BY rank DESC, created_at \
+         LIMIT $3",
+        )
+        .bind(q)
+        .bind(author)
+        .bind(limit)
+        .bind(format!("StartSel={MATCH_START}, StopSel={MATCH_END}"))
+        .fetch_all(pool)
+        .await?;
+        for result in &mut results {
+            result.snippet = highlight(&result.snippet);
+        }
+        Ok(results)
+    }
+}
+
+#[get("trash")]
+async fn trash(
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+
+    let quotes = sqlx::query_as::<_, Quote>(
+        "SELECT * FROM quotes WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC",
     )
-    .bind(Uuid::new_v4())
-    .bind(&form.author)
-    .bind(&form.quote)
-    .fetch_one(&**pool)
-    .await
-    {
-        Ok(quote) => HttpResponse::Created().json(quote),
-        Err(err) => {
-            dbg!(err);
-            HttpResponse::InternalServerError().finish()
+    .fetch_all(&**pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(quotes))
+}
+
+#[put("restore/{id}")]
+async fn restore(
+    id: Path<String>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let id = parse_id(&id)?;
+
+    let quote = Quote::restore(&pool, &id).await?;
+    Ok(HttpResponse::Ok().insert_header(quote.etag()).json(quote))
+}
+
+/// Periodically remove quotes for good once they've been in the trash for
+/// longer than `retention`, along with their history
+pub fn purge_trash(pool: SharedDBPool, retention: TimeDelta) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_hours(1));
+        loop {
+            interval.tick().await;
+            let res = sqlx::query(
+                "WITH purged AS (DELETE FROM quotes WHERE deleted_at < $1 RETURNING id) \
+                 DELETE FROM quote_revisions WHERE quote_id IN (SELECT id FROM purged)",
+            )
+            .bind(Utc::now() - retention)
+            .execute(&**pool)
+            .await;
+            if let Err(err) = res {
+                tracing::error!(error = %err, "couldn't purge the quote book trash");
+            }
         }
+    });
+}
+
+/// The history of a quote, which goes in the trash along with it
+#[get("revisions/{id}")]
+async fn revisions(id: Path<String>, pool: SharedDBPool) -> Result<HttpResponse, QuoteBookError> {
+    let id = parse_id(&id)?;
+    let revisions = sqlx::query_as::<_, Revision>(
+        "SELECT version, author, quote, action, revised_at FROM quote_revisions \
+         WHERE quote_id = $1 \
+           AND quote_id IN (SELECT id FROM quotes WHERE deleted_at IS NULL) \
+         ORDER BY revised_at, version",
+    )
+    .bind(id)
+    .fetch_all(&**pool)
+    .await?;
+
+    if revisions.is_empty() {
+        return Err(QuoteBookError::NotFound);
     }
+    Ok(HttpResponse::Ok().json(revisions))
+}
+
+/// Bring back the text a quote had at an earlier version, which makes for a
+/// new version of its own
+#[put("revert/{id}/{version}")]
+async fn revert(
+    params: Path<(String, i32)>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let (id, version) = params.into_inner();
+    let id = parse_id(&id)?;
+    let expected = expected_versions(&request)?;
+    let revision = sqlx::query_as::<_, Revision>(
+        "SELECT version, author, quote, action, revised_at FROM quote_revisions \
+         WHERE quote_id = $1 AND version = $2 AND action <> 'remove'",
+    )
+    .bind(id)
+    .bind(version)
+    .fetch_one(&**pool)
+    .await?;
+
+    let res = Quote::update(
+        &pool,
+        &id,
+        &revision.author,
+        &revision.quote,
+        expected.as_deref(),
+        Action::Revert,
+    )
+    .await;
+    changed(&pool, &id, res).await
+}
+
+#[post("/draft")]
+async fn draft(
+    pool: SharedDBPool,
+    form: Json<QuoteRequest>,
+) -> Result<HttpResponse, QuoteBookError> {
+    let form = form.validate().map_err(QuoteBookError::Invalid)?;
+
+    let quote = Quote::create(&pool, &form.author, &form.quote).await?;
+    Ok(HttpResponse::Created()
+        .insert_header(quote.etag())
+        .json(quote))
 }
 
 type Token = String;
 type Page = i64;
-type SharedPageCache = Data<Mutex<HashMap<Token, Page>>>;
 
-pub fn shared_page_cache() -> SharedPageCache {
-    Data::new(Mutex::new(HashMap::new()))
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortField {
+    #[default]
+    CreatedAt,
+    Author,
+    Version,
+}
+
+impl SortField {
+    fn column(self) -> &'static str {
+        match self {
+            Self::CreatedAt => "created_at",
+            Self::Author => "author",
+            Self::Version => "version",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Which quotes are listed, and in what order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ListFilters {
+    author: Option<String>,
+    /// Only quotes with this tag
+    tag: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    min_version: Option<i32>,
+    #[serde(default)]
+    sort: SortField,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+impl ListFilters {
+    fn push_conditions(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        builder.push(" WHERE deleted_at IS NULL");
+        if let Some(author) = &self.author {
+            builder
+                .push(" AND lower(author) = lower(")
+                .push_bind(author.clone())
+                .push(")");
+        }
+        // Tags are looked for the way they're saved, so a tag that couldn't
+        // have been saved matches nothing
+        match self.tag.as_deref().map(tags::clean_tag) {
+            Some(Ok(tag)) => {
+                builder
+                    .push(" AND id IN (SELECT quote_id FROM quote_tags WHERE tag = ")
+                    .push_bind(tag)
+                    .push(")");
+            }
+            Some(Err(_)) => {
+                builder.push(" AND FALSE");
+            }
+            None => (),
+        }
+        if let Some(after) = self.created_after {
+            builder.push(" AND created_at >= ").push_bind(after);
+        }
+        if let Some(before) = self.created_before {
+            builder.push(" AND created_at < ").push_bind(before);
+        }
+        if let Some(version) = self.min_version {
+            builder.push(" AND version >= ").push_bind(version);
+        }
+    }
+}
+
+/// Where a page of the list starts, signed so that clients can't make up
+/// their own. It carries the filters the list was asked for with, so every
+/// page comes from the same list.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    /// The quote next to the page, which the page doesn't include
+    boundary: Boundary,
+    direction: Direction,
+    page: Page,
+    page_size: i64,
+    filters: ListFilters,
+}
+
+/// Everything about a quote that the list could be sorted by
+#[derive(Debug, Serialize, Deserialize)]
+struct Boundary {
+    id: Uuid,
+    created_at: DateTime<Utc>,
+    author: String,
+    version: i32,
+}
+
+impl From<&Quote> for Boundary {
+    fn from(quote: &Quote) -> Self {
+        Self {
+            id: quote.id,
+            created_at: quote.created_at,
+            author: quote.author.clone(),
+            version: quote.version,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Direction {
+    /// The page comes after the cursor
+    Next,
+    /// The page comes before the cursor
+    Previous,
+}
+
+impl Cursor {
+    fn sign(self, key: &TokenKey) -> Result<Token, jwt_simple::Error> {
+        key.sign(Claims::with_custom_claims(self, Duration::from_days(1)))
+    }
+
+    fn verify(token: &str, key: &TokenKey) -> Option<Self> {
+        key.verify(token)
+    }
+
+    /// Only include quotes on the far side of the boundary
+    fn push_condition(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        let ascending =
+            (self.filters.order == SortOrder::Asc) == (self.direction == Direction::Next);
+        builder
+            .push(" AND (")
+            .push(self.filters.sort.column())
+            .push(", id) ")
+            .push(if ascending { ">" } else { "<" })
+            .push(" (");
+        match self.filters.sort {
+            SortField::CreatedAt => builder.push_bind(self.boundary.created_at),
+            SortField::Author => builder.push_bind(self.boundary.author.clone()),
+            SortField::Version => builder.push_bind(self.boundary.version),
+        };
+        builder.push(", ").push_bind(self.boundary.id).push(")");
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct ListParams {
-    token: Token,
+    /// Filters and sorting are taken from the token, when there is one
+    token: Option<Token>,
+    /// Defaults to the page size the token was handed out with, and has to
+    /// match it, since the page numbers are counted in pages of that size
+    page_size: Option<i64>,
 }
 
 #[derive(Debug, Serialize)]
 struct ListResponse {
     quotes: Vec<Quote>,
     page: Page,
+    /// How many quotes there are in the whole list
+    total: i64,
+    pages: Page,
     next_token: Option<Token>,
-}
-
-fn random_string(rng: &mut StdRng) -> String {
-    rng.sample_iter(rand::distributions::Alphanumeric)
-        .take(16)
-        .map(char::from)
-        .collect()
+    prev_token: Option<Token>,
 }
 
 #[get("/list")]
 async fn list(
     pool: SharedDBPool,
-    cache: SharedPageCache,
-    rng: SharedRng,
-    query: Option<Query<ListParams>>,
-) -> HttpResponse {
-    const PAGESIZE: Page = 3;
-
-    let count = match sqlx::query_scalar::<_, Page>("SELECT COUNT(*) FROM quotes")
-        .fetch_one(&**pool)
-        .await
-    {
-        Ok(count) => count,
-        Err(err) => {
-            dbg!(err);
-            return HttpResponse::InternalServerError().finish();
-        }
+    key: SharedTokenKey,
+    query: Query<ListParams>,
+    filters: Query<ListFilters>,
+) -> Result<HttpResponse, QuoteBookError> {
+    const DEFAULT_PAGE_SIZE: i64 = 3;
+    const MAX_PAGE_SIZE: i64 = 100;
+
+    let cursor = match &query.token {
+        Some(token) => Some(
+            Cursor::verify(token, &key)
+                .ok_or(QuoteBookError::BadRequest("the token isn't valid"))?,
+        ),
+        None => None,
     };
+    if let (Some(cursor), Some(page_size)) = (&cursor, query.page_size) {
+        if cursor.page_size != page_size {
+            return Err(QuoteBookError::BadRequest(
+                "the page size can't change partway through the list",
+            ));
+        }
+    }
+    let page_size = query
+        .page_size
+        .or(cursor.as_ref().map(|c| c.page_size))
+        .unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&page_size) {
+        return Err(QuoteBookError::BadRequest("the page size is out of range"));
+    }
+
+    let filters = cursor
+        .as_ref()
+        .map_or_else(|| filters.into_inner(), |c| c.filters.clone());
+    let direction = cursor.as_ref().map_or(Direction::Next, |c| c.direction);
+    let page = cursor.as_ref().map_or(1, |c| match c.direction {
+        Direction::Next => c.page + 1,
+        Direction::Previous => c.page - 1,
+    });
 
-    let mut pages = count / PAGESIZE;
-    // Often the last page has less than PAGESIZE
-    if count % PAGESIZE > 0 {
-        pages += 1;
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM quotes");
+    filters.push_conditions(&mut count);
+    let total = count.build_query_scalar::<i64>().fetch_one(&**pool).await?;
+
+    // Going backwards, fetch the page in reverse and turn it around after.
+    // One extra quote tells us whether there's anything beyond the page.
+    let ascending = (filters.order == SortOrder::Asc) == (direction == Direction::Next);
+    let order = if ascending { "ASC" } else { "DESC" };
+    let mut select = QueryBuilder::new("SELECT * FROM quotes");
+    filters.push_conditions(&mut select);
+    if let Some(cursor) = &cursor {
+        cursor.push_condition(&mut select);
     }
+    select
+        .push(format_args!(
+            " ORDER BY {} {order}, id {order} LIMIT ",
+            filters.sort.column()
+        ))
+        .push_bind(page_size + 1);
+    let mut quotes = select.build_query_as::<Quote>().fetch_all(&**pool).await?;
 
-    let page = if let Some(params) = query {
-        let mut map = cache.lock().await;
-        let Some(page) = map.remove(&params.token) else {
-            return HttpResponse::BadRequest().finish();
-        };
-        page
-    } else {
-        1
-    };
+    let limit = usize::try_from(page_size).expect("page size should be positive");
+    let more = quotes.len() > limit;
+    quotes.truncate(limit);
+    if direction == Direction::Previous {
+        quotes.reverse();
+    }
 
-    let next_token = if pages > page {
-        let mut rng = rng.lock().await;
-        let token = random_string(&mut rng);
-        let mut map = cache.lock().await;
-        map.insert(token.clone(), page + 1);
-        Some(token)
-    } else {
-        None
+    let (more_after, more_before) = match direction {
+        Direction::Next => (more, page > 1),
+        Direction::Previous => (true, more),
     };
-
-    match sqlx::query_as::<_, Quote>("SELECT * FROM quotes ORDER BY created_at OFFSET $1 LIMIT $2")
-        .bind((page - 1) * PAGESIZE)
-        .bind(PAGESIZE)
-        .fetch_all(&**pool)
-        .await
-    {
-        Ok(quotes) => HttpResponse::Ok().json(ListResponse {
-            quotes,
+    let token = |quote: &Quote, direction| {
+        Cursor {
+            boundary: quote.into(),
+            direction,
             page,
-            next_token,
-        }),
-        Err(err) => {
-            dbg!(err);
-            HttpResponse::InternalServerError().finish()
+            page_size,
+            filters: filters.clone(),
         }
-    }
+        .sign(&key)
+    };
+    let next_token = quotes
+        .last()
+        .filter(|_| more_after)
+        .map(|last| token(last, Direction::Next))
+        .transpose()?;
+    let prev_token = quotes
+        .first()
+        .filter(|_| more_before)
+        .map(|first| token(first, Direction::Previous))
+        .transpose()?;
+
+    Ok(HttpResponse::Ok().json(ListResponse {
+        quotes,
+        page,
+        total,
+        pages: (total + page_size - 1) / page_size,
+        next_token,
+        prev_token,
+    }))
 }
 
 pub fn scope() -> Scope {
+    // Requests the extractors can't make sense of get problem bodies too
     Scope::new("/19")
+        .app_data(JsonConfig::default().error_handler(|err, _| QuoteBookError::from(err).into()))
+        .app_data(QueryConfig::default().error_handler(|_, _| {
+            QuoteBookError::BadRequest("the query string isn't valid").into()
+        }))
+        .app_data(
+            PathConfig::default()
+                .error_handler(|_, _| QuoteBookError::BadRequest("the path isn't valid").into()),
+        )
         .service(reset)
         .service(cite)
         .service(remove)
         .service(undo)
         .service(draft)
         .service(list)
+        .service(issue_token)
+        .service(revisions)
+        .service(revert)
+        .service(trash)
+        .service(restore)
+        .service(search)
+        .service(transfer::export)
+        .service(transfer::import)
+        .service(featured::random)
+        .service(featured::daily)
+        .service(tags::list_tags)
+        .service(tags::quote_tags)
+        .service(tags::tag_quote)
+        .service(tags::untag_quote)
+        .service(collections::list_collections)
+        .service(collections::create_collection)
+        .service(collections::show_collection)
+        .service(collections::delete_collection)
+        .service(collections::add_quote)
+        .service(collections::remove_quote)
+        .service(collections::reorder_quotes)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::TestRequest;
+    use actix_web::ResponseError;
+
+    use super::*;
+
+    fn request_with_token(token: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            .to_http_request()
+    }
+
+    #[test]
+    fn roles_grant_everything_below_them() {
+        let key = shared_token_key(None);
+        let request_with = |role| {
+            let claims = Claims::with_custom_claims(Grant { role }, Duration::from_mins(5));
+            request_with_token(&key.sign(claims).unwrap())
+        };
+
+        assert!(authorize(&request_with(Role::Admin), &key, Role::Editor).is_ok());
+        assert!(authorize(&request_with(Role::Editor), &key, Role::Editor).is_ok());
+
+        let forbidden = authorize(&request_with(Role::Reader), &key, Role::Editor).unwrap_err();
+        assert_eq!(StatusCode::FORBIDDEN, forbidden.status_code());
+
+        let missing = authorize(
+            &TestRequest::default().to_http_request(),
+            &key,
+            Role::Reader,
+        );
+        assert_eq!(StatusCode::UNAUTHORIZED, missing.unwrap_err().status_code());
+    }
+
+    #[test]
+    fn grants_have_to_come_from_the_quote_book() {
+        let secret = "hunter2".to_string();
+        let key = shared_token_key(Some(secret.clone()));
+
+        // Signed the way /16/wrap signs whatever it's sent, even with the
+        // same secret
+        let wrapped = HS256Key::from_bytes(secret.as_bytes())
+            .authenticate(Claims::with_custom_claims(
+                serde_json::json!({ "role": "admin" }),
+                Duration::from_mins(5),
+            ))
+            .unwrap();
+        let forged = authorize(&request_with_token(&wrapped), &key, Role::Reader);
+        assert_eq!(StatusCode::UNAUTHORIZED, forged.unwrap_err().status_code());
+
+        let claims =
+            Claims::with_custom_claims(Grant { role: Role::Admin }, Duration::from_mins(5));
+        let granted = key.sign(claims).unwrap();
+        assert!(authorize(&request_with_token(&granted), &key, Role::Admin).is_ok());
+    }
+
+    #[test]
+    fn only_the_admin_key_matches() {
+        let admin_key = AdminKey(Some("open sesame".to_string()));
+        assert!(admin_key.matches(Some("open sesame")));
+        assert!(!admin_key.matches(Some("open sesame!")));
+        assert!(!admin_key.matches(Some("")));
+        assert!(!admin_key.matches(None));
+        assert!(!AdminKey(None).matches(Some("")));
+    }
+
+    #[test]
+    fn cursors_only_verify_with_the_key_that_signed_them() {
+        let key = shared_token_key(None);
+        let quote = Quote {
+            id: Uuid::new_v4(),
+            author: "Santa".into(),
+            quote: "Ho ho ho".into(),
+            created_at: Utc::now(),
+            version: 1,
+            deleted_at: None,
+        };
+        let filters = ListFilters {
+            author: Some("Santa".into()),
+            sort: SortField::Version,
+            ..ListFilters::default()
+        };
+        let token = Cursor {
+            boundary: (&quote).into(),
+            direction: Direction::Next,
+            page: 2,
+            page_size: 5,
+            filters,
+        }
+        .sign(&key)
+        .unwrap();
+
+        let cursor = Cursor::verify(&token, &key).unwrap();
+        assert_eq!(quote.id, cursor.boundary.id);
+        assert_eq!(Direction::Next, cursor.direction);
+        assert_eq!(SortField::Version, cursor.filters.sort);
+        assert_eq!(Some("Santa"), cursor.filters.author.as_deref());
+        assert!(Cursor::verify(&token, &shared_token_key(None)).is_none());
+    }
+
+    #[test]
+    fn tags_that_couldnt_be_saved_match_nothing() {
+        let sql = |tag: &str| {
+            let mut builder = QueryBuilder::new("");
+            ListFilters {
+                tag: Some(tag.to_string()),
+                ..ListFilters::default()
+            }
+            .push_conditions(&mut builder);
+            builder.sql().to_string()
+        };
+
+        assert_eq!(
+            " WHERE deleted_at IS NULL \
+             AND id IN (SELECT quote_id FROM quote_tags WHERE tag = $1)",
+            sql(" Christmas ")
+        );
+        assert_eq!(" WHERE deleted_at IS NULL AND FALSE", sql("Christ\nmas"));
+        assert_eq!(" WHERE deleted_at IS NULL AND FALSE", sql(&"x".repeat(51)));
+    }
+
+    #[test]
+    fn snippets_are_escaped_apart_from_the_marks() {
+        assert_eq!(
+            "<mark>Cookies</mark> &amp; milk &lt;b&gt;&quot;rock&quot;&lt;/b&gt; &#39;n&#39; roll",
+            highlight("\u{2}Cookies\u{3} & milk <b>\"rock\"</b> 'n' roll")
+        );
+    }
+
+    /// Quotes matching in their text rank above ones matching in the author's
+    /// name, and markup in them comes back escaped. Run with `--ignored`
+    /// against a database at `DATABASE_URL`.
+    #[ignore = "needs a database"]
+    #[sqlx::test]
+    async fn search_ranks_quote_text_above_authors(pool: PgPool) {
+        for (author, quote) in [
+            ("Cookie Monster", "Om nom nom"),
+            ("Elf", "I like cookies"),
+            (
+                "Santa",
+                "Cookies & milk <script>alert(1)</script> make \"cookies\" better",
+            ),
+        ] {
+            Quote::create(&pool, author, quote).await.unwrap();
+        }
+
+        let results = SearchResult::find(&pool, "cookie", None, 10).await.unwrap();
+        let authors: Vec<_> = results.iter().map(|r| r.quote.author.as_str()).collect();
+        assert_eq!(vec!["Santa", "Elf", "Cookie Monster"], authors);
+        assert!(results.windows(2).all(|w| w[0].rank >= w[1].rank));
+
+        let snippet = &results[0].snippet;
+        assert!(snippet.starts_with("<mark>Cookies</mark> &amp; milk"));
+        assert!(snippet.contains("&quot;<mark>cookies</mark>&quot;"));
+        assert!(!snippet.contains("<script>"));
+        assert_eq!("Om nom nom", results[2].snippet);
+    }
+
+    #[test]
+    fn cursors_page_in_the_direction_of_the_sort() {
+        let mut builder = QueryBuilder::new("");
+        let cursor = Cursor {
+            boundary: Boundary {
+                id: Uuid::nil(),
+                created_at: Utc::now(),
+                author: "Santa".into(),
+                version: 1,
+            },
+            direction: Direction::Previous,
+            page: 2,
+            page_size: 5,
+            filters: ListFilters {
+                sort: SortField::Author,
+                order: SortOrder::Desc,
+                ..ListFilters::default()
+            },
+        };
+        cursor.push_condition(&mut builder);
+
+        assert_eq!(" AND (author, id) > ($1, $2)", builder.sql());
+    }
+
+    #[test]
+    fn quotes_are_cleaned_up_or_rejected() {
+        let request = |author: &str, quote: &str| QuoteRequest {
+            author: author.to_string(),
+            quote: quote.to_string(),
+        };
+
+        // "e" followed by a combining acute accent comes out as "é"
+        let cleaned = request("  Rene\u{301} ", "Ho ho\nho").validate().unwrap();
+        assert_eq!("Ren\u{e9}", cleaned.author);
+        assert_eq!("Ho ho\nho", cleaned.quote);
+
+        let errors = request(" ", &"ho".repeat(MAX_QUOTE_LENGTH))
+            .validate()
+            .unwrap_err();
+        let fields: Vec<_> = errors.iter().map(|e| e.field).collect();
+        assert_eq!(vec!["author", "quote"], fields);
+
+        let errors = request("San\nta", "Ho\u{7}").validate().unwrap_err();
+        assert_eq!(2, errors.len());
+        assert!(errors
+            .iter()
+            .all(|e| e.message.contains("control characters")));
+        let errors = request("San\tta", "Ho\u{7}").validate().unwrap_err();
+        assert_eq!(2, errors.len());
+        assert!(errors
+            .iter()
+            .all(|e| e.message.contains("control characters")));
+    }
+
+    #[test]
+    fn line_breaks_come_out_the_same_from_any_system() {
+        let request = QuoteRequest {
+            author: "Santa\r\n".to_string(),
+            quote: "Ho\r\n\tho\rho".to_string(),
+        };
+        let cleaned = request.validate().unwrap();
+        assert_eq!("Santa", cleaned.author);
+        assert_eq!("Ho\n\tho\nho", cleaned.quote);
+
+        let request = QuoteRequest {
+            author: "San\r\nta".to_string(),
+            quote: "Ho ho ho".to_string(),
+        };
+        let errors = request.validate().unwrap_err();
+        assert_eq!("author", errors[0].field);
+    }
+
+    #[test]
+    fn if_match_gives_the_expected_versions() {
+        let expected = |value: Option<&str>| {
+            let mut request = TestRequest::default();
+            if let Some(value) = value {
+                request = request.insert_header((header::IF_MATCH, value));
+            }
+            expected_versions(&request.to_http_request()).map_err(|e| e.status_code())
+        };
+
+        assert_eq!(Ok(None), expected(None));
+        assert_eq!(Ok(None), expected(Some("*")));
+        assert_eq!(Ok(Some(vec![3])), expected(Some("\"3\"")));
+        assert_eq!(
+            Ok(Some(vec![1, 2])),
+            expected(Some("\"1\", W/\"4\", \"2\""))
+        );
+        assert_eq!(Err(StatusCode::BAD_REQUEST), expected(Some("3")));
+    }
 }
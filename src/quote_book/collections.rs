@@ -0,0 +1,399 @@
+//! Curated collections of quotes, kept in whatever order the editors choose
+
+use actix_web::http::header;
+use actix_web::web::{Json, Path};
+use actix_web::{delete, get, post, put, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use super::{
+    authorize, clean, parse_id, FieldError, Quote, QuoteBookError, Role, SharedDBPool,
+    SharedTokenKey,
+};
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+
+#[derive(Debug, Serialize, FromRow)]
+struct Collection {
+    id: Uuid,
+    name: String,
+    description: String,
+    created_at: DateTime<Utc>,
+    /// How many quotes are in the collection, leaving out any in the trash
+    size: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct CollectionView {
+    #[serde(flatten)]
+    collection: Collection,
+    /// In the collection's order
+    quotes: Vec<Quote>,
+}
+
+/// A quote in a collection, which stays there while it's in the trash
+#[derive(Debug, FromRow)]
+struct Member {
+    quote_id: Uuid,
+    trashed: bool,
+}
+
+const SELECT_COLLECTIONS: &str = "SELECT c.*, COUNT(q.id) AS size FROM collections c \
+     LEFT JOIN collection_quotes cq ON cq.collection_id = c.id \
+     LEFT JOIN quotes q ON q.id = cq.quote_id AND q.deleted_at IS NULL";
+
+async fn view(pool: &PgPool, id: &Uuid) -> Result<CollectionView, QuoteBookError> {
+    let collection = sqlx::query_as::<_, Collection>(&format!(
+        "{SELECT_COLLECTIONS} WHERE c.id = $1 GROUP BY c.id"
+    ))
+    .bind(id)
+    .fetch_one(pool)
+    .await?;
+    let quotes = sqlx::query_as::<_, Quote>(
+        "SELECT q.* FROM collection_quotes cq JOIN quotes q ON q.id = cq.quote_id \
+         WHERE cq.collection_id = $1 AND q.deleted_at IS NULL ORDER BY cq.position",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(CollectionView { collection, quotes })
+}
+
+/// Put the quotes of a collection in a new order, worked out by `change` from
+/// the quotes it has now, and respond with how it turned out. `change` can
+/// turn the request down instead. Any quote being `added` has to exist and be
+/// out of the trash, and stays that way until the change is made.
+async fn rearrange(
+    pool: &PgPool,
+    id: &Uuid,
+    added: Option<&Uuid>,
+    change: impl FnOnce(&[Member]) -> Result<Vec<Uuid>, QuoteBookError>,
+) -> Result<HttpResponse, QuoteBookError> {
+    let mut tx = pool.begin().await?;
+    // Hold on to the collection, so that changes made at the same time don't
+    // undo each other
+    sqlx::query("SELECT id FROM collections WHERE id = $1 FOR UPDATE")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if let Some(quote_id) = added {
+        sqlx::query("SELECT id FROM quotes WHERE id = $1 AND deleted_at IS NULL FOR SHARE")
+            .bind(quote_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    }
+
+    let members = sqlx::query_as::<_, Member>(
+        "SELECT cq.quote_id, q.deleted_at IS NOT NULL AS trashed \
+         FROM collection_quotes cq JOIN quotes q ON q.id = cq.quote_id \
+         WHERE cq.collection_id = $1 ORDER BY cq.position",
+    )
+    .bind(id)
+    .fetch_all(&mut *tx)
+    .await?;
+    let order = change(&members)?;
+
+    sqlx::query("DELETE FROM collection_quotes WHERE collection_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO collection_quotes (collection_id, quote_id, position) \
+         SELECT $1, quote_id, position \
+         FROM unnest($2::UUID[]) WITH ORDINALITY AS o(quote_id, position)",
+    )
+    .bind(id)
+    .bind(&order)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(view(pool, id).await?))
+}
+
+/// The collection in the order given by `wanted`, which has to name every
+/// quote in it that isn't in the trash exactly once. Quotes in the trash go at
+/// the end, in the order they were in.
+fn reordered(members: &[Member], wanted: &[Uuid]) -> Option<Vec<Uuid>> {
+    let mut current: Vec<_> = members
+        .iter()
+        .filter(|m| !m.trashed)
+        .map(|m| m.quote_id)
+        .collect();
+    let mut sorted = wanted.to_vec();
+    current.sort_unstable();
+    sorted.sort_unstable();
+    if current != sorted {
+        return None;
+    }
+
+    let trashed = members.iter().filter(|m| m.trashed).map(|m| m.quote_id);
+    Some(wanted.iter().copied().chain(trashed).collect())
+}
+
+/// The collection with `quote_id` put at `position`, counting from 1, or at
+/// the end. Quotes in the trash can't be seen in the collection, so they
+/// don't count towards the position.
+fn inserted(members: &[Member], quote_id: Uuid, position: Option<usize>) -> Vec<Uuid> {
+    let others: Vec<_> = members.iter().filter(|m| m.quote_id != quote_id).collect();
+    let shown = others.iter().filter(|m| !m.trashed).count();
+    let at = position.map_or(shown, |p| p.saturating_sub(1).min(shown));
+    let index = others
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| !m.trashed)
+        .nth(at)
+        .map_or(others.len(), |(i, _)| i);
+
+    let mut order: Vec<_> = others.iter().map(|m| m.quote_id).collect();
+    order.insert(index, quote_id);
+    order
+}
+
+#[derive(Debug, Deserialize)]
+struct CollectionRequest {
+    name: String,
+    #[serde(default)]
+    description: String,
+}
+
+impl CollectionRequest {
+    fn validate(&self) -> Result<Self, Vec<FieldError>> {
+        let name = clean("name", &self.name, MAX_NAME_LENGTH, false);
+        // Unlike the name, the description can be left blank
+        let description = if self.description.trim().is_empty() {
+            Ok(String::new())
+        } else {
+            clean(
+                "description",
+                &self.description,
+                MAX_DESCRIPTION_LENGTH,
+                true,
+            )
+        };
+        match (name, description) {
+            (Ok(name), Ok(description)) => Ok(Self { name, description }),
+            (name, description) => Err(name.err().into_iter().chain(description.err()).collect()),
+        }
+    }
+}
+
+#[get("collections")]
+async fn list_collections(pool: SharedDBPool) -> Result<HttpResponse, QuoteBookError> {
+    let collections = sqlx::query_as::<_, Collection>(&format!(
+        "{SELECT_COLLECTIONS} GROUP BY c.id ORDER BY c.name"
+    ))
+    .fetch_all(&**pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(collections))
+}
+
+#[post("collections")]
+async fn create_collection(
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+    form: Json<CollectionRequest>,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let form = form.validate().map_err(QuoteBookError::Invalid)?;
+
+    let collection = sqlx::query_as::<_, Collection>(
+        "INSERT INTO collections (id, name, description) VALUES ($1, $2, $3) \
+         RETURNING *, 0::BIGINT AS size",
+    )
+    .bind(Uuid::new_v4())
+    .bind(&form.name)
+    .bind(&form.description)
+    .fetch_one(&**pool)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(err) if err.is_unique_violation() => {
+            QuoteBookError::Conflict("there's already a collection with this name")
+        }
+        err => err.into(),
+    })?;
+
+    Ok(HttpResponse::Created()
+        .insert_header((
+            header::LOCATION,
+            format!("/19/collections/{}", collection.id),
+        ))
+        .json(collection))
+}
+
+#[get("collections/{id}")]
+async fn show_collection(
+    id: Path<String>,
+    pool: SharedDBPool,
+) -> Result<HttpResponse, QuoteBookError> {
+    let id = parse_id(&id)?;
+    Ok(HttpResponse::Ok().json(view(&pool, &id).await?))
+}
+
+#[delete("collections/{id}")]
+async fn delete_collection(
+    id: Path<String>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let id = parse_id(&id)?;
+
+    let done = sqlx::query("DELETE FROM collections WHERE id = $1")
+        .bind(id)
+        .execute(&**pool)
+        .await?;
+    if done.rows_affected() == 0 {
+        return Err(QuoteBookError::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Deserialize)]
+struct AddQuote {
+    quote_id: Uuid,
+    /// Where the quote goes in the collection, counting from 1. Defaults to
+    /// the end.
+    position: Option<usize>,
+}
+
+/// Add a quote to a collection, or move it if it's already there
+#[post("collections/{id}/quotes")]
+async fn add_quote(
+    id: Path<String>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+    form: Json<AddQuote>,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let id = parse_id(&id)?;
+    let AddQuote { quote_id, position } = form.into_inner();
+
+    rearrange(&pool, &id, Some(&quote_id), |members| {
+        Ok(inserted(members, quote_id, position))
+    })
+    .await
+}
+
+#[delete("collections/{id}/quotes/{quote_id}")]
+async fn remove_quote(
+    params: Path<(String, String)>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let (id, quote_id) = params.into_inner();
+    let (id, quote_id) = (parse_id(&id)?, parse_id(&quote_id)?);
+
+    rearrange(&pool, &id, None, |members| {
+        if !members.iter().any(|m| m.quote_id == quote_id) {
+            return Err(QuoteBookError::NotFound);
+        }
+        Ok(members
+            .iter()
+            .map(|m| m.quote_id)
+            .filter(|&q| q != quote_id)
+            .collect())
+    })
+    .await
+}
+
+/// Put the quotes of a collection in the order given
+#[put("collections/{id}/quotes")]
+async fn reorder_quotes(
+    id: Path<String>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+    order: Json<Vec<Uuid>>,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let id = parse_id(&id)?;
+
+    rearrange(&pool, &id, None, |members| {
+        reordered(members, &order).ok_or_else(|| {
+            QuoteBookError::Invalid(vec![FieldError {
+                field: "quotes",
+                message: "must list every quote in the collection once".to_string(),
+            }])
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reordering_has_to_name_every_quote_once() {
+        let [a, b, c] = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        let members = [
+            Member {
+                quote_id: a,
+                trashed: false,
+            },
+            Member {
+                quote_id: b,
+                trashed: true,
+            },
+            Member {
+                quote_id: c,
+                trashed: false,
+            },
+        ];
+
+        // Quotes in the trash keep their place at the end
+        assert_eq!(Some(vec![c, a, b]), reordered(&members, &[c, a]));
+        assert_eq!(None, reordered(&members, &[c]));
+        assert_eq!(None, reordered(&members, &[c, c]));
+        assert_eq!(None, reordered(&members, &[c, a, b]));
+    }
+
+    #[test]
+    fn positions_only_count_quotes_out_of_the_trash() {
+        let [a, b, c, d] = [
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        ];
+        let members = [
+            Member {
+                quote_id: a,
+                trashed: true,
+            },
+            Member {
+                quote_id: b,
+                trashed: false,
+            },
+            Member {
+                quote_id: c,
+                trashed: true,
+            },
+            Member {
+                quote_id: d,
+                trashed: false,
+            },
+        ];
+
+        let new = Uuid::new_v4();
+        assert_eq!(vec![a, new, b, c, d], inserted(&members, new, Some(1)));
+        assert_eq!(vec![a, b, c, new, d], inserted(&members, new, Some(2)));
+        assert_eq!(vec![a, b, c, d, new], inserted(&members, new, Some(3)));
+        assert_eq!(vec![a, b, c, d, new], inserted(&members, new, None));
+
+        // Moving a quote that's already there
+        assert_eq!(vec![a, c, d, b], inserted(&members, b, Some(2)));
+        assert_eq!(vec![a, d, b, c], inserted(&members, d, Some(1)));
+    }
+}
@@ -0,0 +1,217 @@
+//! Everything that can go wrong in the quote book, and how clients are told
+//! about it: with a JSON problem body, as in RFC 9457
+
+use std::fmt::{self, Display, Formatter};
+
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+
+use super::transfer::RowError;
+use super::FieldError;
+
+#[derive(Debug)]
+pub(super) enum QuoteBookError {
+    /// The request couldn't be made sense of
+    BadRequest(&'static str),
+    /// No token, or one that isn't valid
+    Unauthorized,
+    /// A valid token, but for a role that can't do this
+    Forbidden,
+    NotFound,
+    /// The quote isn't at the version the client expected any more
+    PreconditionFailed,
+    /// Something with the same name or ID already exists
+    Conflict(&'static str),
+    UnsupportedMediaType,
+    Invalid(Vec<FieldError>),
+    /// Rows of an import that couldn't be read or saved
+    InvalidRows(Vec<RowError>),
+    /// The database can't be reached, which is hopefully only for a while
+    Unavailable(sqlx::Error),
+    Database(sqlx::Error),
+    Token(jwt_simple::Error),
+}
+
+impl From<sqlx::Error> for QuoteBookError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::Database(err) if err.is_unique_violation() => {
+                Self::Conflict("it already exists")
+            }
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed => Self::Unavailable(err),
+            _ => Self::Database(err),
+        }
+    }
+}
+
+/// Bodies the JSON extractor couldn't read
+impl From<JsonPayloadError> for QuoteBookError {
+    fn from(err: JsonPayloadError) -> Self {
+        match err {
+            JsonPayloadError::ContentType => Self::UnsupportedMediaType,
+            JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+                Self::BadRequest("the body is too large")
+            }
+            _ => Self::BadRequest("the body isn't the JSON this expects"),
+        }
+    }
+}
+
+impl From<jwt_simple::Error> for QuoteBookError {
+    fn from(err: jwt_simple::Error) -> Self {
+        Self::Token(err)
+    }
+}
+
+impl Display for QuoteBookError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::BadRequest(detail) | Self::Conflict(detail) => write!(f, "{detail}"),
+            Self::Unauthorized => write!(f, "a valid token is needed"),
+            Self::Forbidden => write!(f, "the token's role isn't allowed to do this"),
+            Self::NotFound => write!(f, "there's nothing here"),
+            Self::PreconditionFailed => write!(f, "the quote has changed since"),
+            Self::UnsupportedMediaType => write!(f, "the content type isn't supported"),
+            Self::Invalid(errors) => write!(f, "{} fields are invalid", errors.len()),
+            Self::InvalidRows(errors) => write!(f, "{} rows are invalid", errors.len()),
+            Self::Unavailable(err) => write!(f, "database unavailable: {err}"),
+            Self::Database(err) => write!(f, "database error: {err}"),
+            Self::Token(err) => write!(f, "couldn't sign token: {err}"),
+        }
+    }
+}
+
+/// What's wrong with each part of the request, when there's more than one
+/// thing
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Errors<'a> {
+    Fields(&'a [FieldError]),
+    Rows(&'a [RowError]),
+}
+
+#[derive(Debug, Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Errors<'a>>,
+}
+
+impl ResponseError for QuoteBookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Invalid(_) | Self::InvalidRows(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Database(_) | Self::Token(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        // What went wrong inside is for the logs, not for clients
+        let detail = match self {
+            Self::Unavailable(_) => {
+                tracing::warn!(error = %self, "quote book database unavailable");
+                None
+            }
+            Self::Database(_) | Self::Token(_) => {
+                tracing::error!(error = %self, "quote book request failed");
+                None
+            }
+            _ => Some(self.to_string()),
+        };
+        let errors = match self {
+            Self::Invalid(errors) => Some(Errors::Fields(errors)),
+            Self::InvalidRows(errors) => Some(Errors::Rows(errors)),
+            _ => None,
+        };
+
+        HttpResponse::build(status)
+            .content_type("application/problem+json")
+            .json(Problem {
+                kind: "about:blank",
+                title: status.canonical_reason().unwrap_or("Error"),
+                status: status.as_u16(),
+                detail,
+                errors,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::MessageBody;
+    use actix_web::http::header;
+
+    use super::*;
+
+    #[test]
+    fn errors_are_described_as_problems() {
+        let response = QuoteBookError::Invalid(vec![FieldError {
+            field: "author",
+            message: "must not be empty".to_string(),
+        }])
+        .error_response();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+        assert_eq!(
+            Some("application/problem+json"),
+            response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+        );
+
+        let body = response.into_body().try_into_bytes().unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(422, problem["status"]);
+        assert_eq!("Unprocessable Entity", problem["title"]);
+        assert_eq!("author", problem["errors"][0]["field"]);
+    }
+
+    #[test]
+    fn lost_connections_are_temporary() {
+        let error = QuoteBookError::from(sqlx::Error::PoolTimedOut);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, error.status_code());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            QuoteBookError::from(sqlx::Error::RowNotFound).status_code()
+        );
+    }
+
+    #[test]
+    fn extractor_errors_are_problems_too() {
+        assert_eq!(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            QuoteBookError::from(JsonPayloadError::ContentType).status_code()
+        );
+
+        let syntax = serde_json::from_str::<u8>("{").unwrap_err();
+        let response = QuoteBookError::from(JsonPayloadError::Deserialize(syntax)).error_response();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!(
+            Some("application/problem+json"),
+            response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+        );
+    }
+}
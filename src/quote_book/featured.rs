@@ -0,0 +1,220 @@
+//! Quotes picked out for readers: one at random, or the quote of the day
+
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::web::Query;
+use actix_web::{get, HttpResponse};
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, QueryBuilder};
+
+use super::{ListFilters, Quote, QuoteBookError, SharedDBPool};
+use crate::game::SharedRng;
+
+#[derive(Debug, Deserialize)]
+struct RandomParams {
+    author: Option<String>,
+    tag: Option<String>,
+}
+
+#[get("random")]
+async fn random(
+    pool: SharedDBPool,
+    rng: SharedRng,
+    query: Query<RandomParams>,
+) -> Result<HttpResponse, QuoteBookError> {
+    let RandomParams { author, tag } = query.into_inner();
+    let filters = ListFilters {
+        author,
+        tag,
+        ..ListFilters::default()
+    };
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM quotes");
+    filters.push_conditions(&mut count);
+    let total = count.build_query_scalar::<i64>().fetch_one(&**pool).await?;
+    if total == 0 {
+        return Err(QuoteBookError::NotFound);
+    }
+    let offset = rng.lock().await.gen_range(0..total);
+
+    // Postgres still reads through every row before the offset, which is
+    // fine for a quote book but would want something like TABLESAMPLE for a
+    // much bigger table
+    let mut select = QueryBuilder::new("SELECT * FROM quotes");
+    filters.push_conditions(&mut select);
+    select
+        .push(" ORDER BY created_at, id OFFSET ")
+        .push_bind(offset)
+        .push(" LIMIT 1");
+    // Not found if the quote was taken away since counting
+    let quote = select.build_query_as::<Quote>().fetch_one(&**pool).await?;
+
+    Ok(HttpResponse::Ok().insert_header(quote.etag()).json(quote))
+}
+
+/// The date it is in `tz` at `now`, and how much of that date is left there
+fn day_at(now: DateTime<Utc>, tz: Tz) -> (NaiveDate, TimeDelta) {
+    let date = now.with_timezone(&tz).date_naive();
+    let tomorrow = date
+        .succ_opt()
+        .expect("dates should go on for a while yet")
+        .and_time(NaiveTime::MIN);
+    // When the clocks go forward at midnight, the next day starts at whatever
+    // time they go forward to
+    let end = (0..24)
+        .find_map(|hours| {
+            tz.from_local_datetime(&(tomorrow + TimeDelta::hours(hours)))
+                .earliest()
+        })
+        .expect("a day should start at some point");
+
+    (date, end.with_timezone(&Utc) - now)
+}
+
+#[derive(Debug, Deserialize)]
+struct DailyParams {
+    /// Name of a time zone, like `Europe/London`. Defaults to UTC.
+    tz: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DailyQuote {
+    date: NaiveDate,
+    timezone: Tz,
+    quote: Quote,
+}
+
+/// The quote picked for `date` in `timezone`, unless it's in the trash now
+async fn picked(
+    pool: &PgPool,
+    date: NaiveDate,
+    timezone: Tz,
+) -> Result<Option<Quote>, sqlx::Error> {
+    sqlx::query_as::<_, Quote>(
+        "SELECT q.* FROM daily_quotes d JOIN quotes q ON q.id = d.quote_id \
+         WHERE d.date = $1 AND d.timezone = $2 AND q.deleted_at IS NULL",
+    )
+    .bind(date)
+    .bind(timezone.name())
+    .fetch_optional(pool)
+    .await
+}
+
+/// The quote of the day, which is the same for everyone whose day it is.
+/// Once it's picked it's kept, so that it doesn't change while it's cached,
+/// unless it goes in the trash.
+#[get("daily")]
+async fn daily(
+    pool: SharedDBPool,
+    query: Query<DailyParams>,
+) -> Result<HttpResponse, QuoteBookError> {
+    let timezone = match query.tz.as_deref().map(str::parse::<Tz>) {
+        None => Tz::UTC,
+        Some(Ok(tz)) => tz,
+        Some(Err(_)) => return Err(QuoteBookError::BadRequest("not a known time zone")),
+    };
+    let (date, left) = day_at(Utc::now(), timezone);
+
+    let quote = match picked(&pool, date, timezone).await? {
+        Some(quote) => quote,
+        None => pick(&pool, date, timezone).await?,
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(u32::try_from(left.num_seconds()).unwrap_or(0)),
+        ]))
+        .json(DailyQuote {
+            date,
+            timezone,
+            quote,
+        }))
+}
+
+/// Pick the quote of the day for `date` in `timezone`, and keep it
+async fn pick(pool: &PgPool, date: NaiveDate, timezone: Tz) -> Result<Quote, QuoteBookError> {
+    // Hashing each quote with the date shuffles them into a new order every
+    // day, so the same quote comes up wherever it's that date
+    let quote = sqlx::query_as::<_, Quote>(
+        "SELECT * FROM quotes WHERE deleted_at IS NULL \
+         ORDER BY md5(id::TEXT || $1), id LIMIT 1",
+    )
+    .bind(date.to_string())
+    .fetch_one(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+    // Nobody's day is more than a day behind anyone else's
+    sqlx::query("DELETE FROM daily_quotes WHERE date < $1")
+        .bind(date - TimeDelta::days(2))
+        .execute(&mut *tx)
+        .await?;
+    // Someone else may have picked one at the same time, which only gets
+    // replaced if it's gone in the trash since
+    sqlx::query(
+        "INSERT INTO daily_quotes (date, timezone, quote_id) VALUES ($1, $2, $3) \
+         ON CONFLICT (date, timezone) DO UPDATE SET quote_id = EXCLUDED.quote_id \
+         WHERE daily_quotes.quote_id NOT IN (SELECT id FROM quotes WHERE deleted_at IS NULL)",
+    )
+    .bind(date)
+    .bind(timezone.name())
+    .bind(quote.id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    // Whichever pick was kept
+    picked(pool, date, timezone)
+        .await?
+        .ok_or(QuoteBookError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_change_at_midnight_wherever_you_are() {
+        let now = "2024-12-24T20:00:00Z".parse().unwrap();
+        let christmas_eve = NaiveDate::from_ymd_opt(2024, 12, 24).unwrap();
+
+        assert_eq!((christmas_eve, TimeDelta::hours(4)), day_at(now, Tz::UTC));
+        assert_eq!(
+            (christmas_eve.succ_opt().unwrap(), TimeDelta::hours(15)),
+            day_at(now, Tz::Pacific__Auckland)
+        );
+
+        // Chile skipped from midnight to one o'clock when summer time started
+        let now = "2024-09-07T16:00:00Z".parse().unwrap();
+        assert_eq!(TimeDelta::hours(12), day_at(now, Tz::America__Santiago).1);
+    }
+
+    /// Run with `--ignored` against a database at `DATABASE_URL`
+    #[ignore = "needs a database"]
+    #[sqlx::test]
+    async fn the_quote_of_the_day_stays_picked(pool: PgPool) {
+        let date = NaiveDate::from_ymd_opt(2024, 12, 24).unwrap();
+        Quote::create(&pool, "Santa", "Ho ho ho").await.unwrap();
+        let first = pick(&pool, date, Tz::UTC).await.unwrap();
+
+        // However many quotes come along later in the day
+        for i in 0..20 {
+            Quote::create(&pool, "Elf", &format!("Quote {i}"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            first.id,
+            picked(&pool, date, Tz::UTC).await.unwrap().unwrap().id
+        );
+        assert_eq!(first.id, pick(&pool, date, Tz::UTC).await.unwrap().id);
+
+        // Until it goes in the trash
+        Quote::delete(&pool, &first.id, None).await.unwrap();
+        assert!(picked(&pool, date, Tz::UTC).await.unwrap().is_none());
+        assert_ne!(first.id, pick(&pool, date, Tz::UTC).await.unwrap().id);
+    }
+}
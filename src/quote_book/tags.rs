@@ -0,0 +1,138 @@
+//! Tagging quotes by theme. Listing the quotes with a tag goes through the
+//! list, with the `tag` filter.
+
+use actix_web::web::Path;
+use actix_web::{delete, get, put, HttpRequest, HttpResponse};
+use serde::Serialize;
+use sqlx::{FromRow, PgExecutor, PgPool};
+use uuid::Uuid;
+
+use super::{
+    authorize, clean, parse_id, FieldError, QuoteBookError, Role, SharedDBPool, SharedTokenKey,
+};
+
+const MAX_TAG_LENGTH: usize = 50;
+
+/// Tags are compared without regard to case, so they're kept in lowercase
+pub(super) fn clean_tag(tag: &str) -> Result<String, FieldError> {
+    clean("tag", tag, MAX_TAG_LENGTH, false).map(|tag| tag.to_lowercase())
+}
+
+#[derive(Debug, Serialize, FromRow)]
+struct Tag {
+    name: String,
+    /// How many quotes have the tag, leaving out any in the trash
+    quotes: i64,
+}
+
+async fn tags_of<'e>(executor: impl PgExecutor<'e>, id: &Uuid) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT tag FROM quote_tags WHERE quote_id = $1 ORDER BY tag")
+        .bind(id)
+        .fetch_all(executor)
+        .await
+}
+
+async fn check_quote<'e>(executor: impl PgExecutor<'e>, id: &Uuid) -> Result<(), QuoteBookError> {
+    sqlx::query("SELECT id FROM quotes WHERE id = $1 AND deleted_at IS NULL")
+        .bind(id)
+        .fetch_optional(executor)
+        .await?
+        .ok_or(QuoteBookError::NotFound)?;
+    Ok(())
+}
+
+/// Tag a quote, giving back all its tags
+async fn add_tag(pool: &PgPool, id: &Uuid, tag: &str) -> Result<Vec<String>, QuoteBookError> {
+    let mut tx = pool.begin().await?;
+    check_quote(&mut *tx, id).await?;
+
+    sqlx::query("INSERT INTO tags (name) VALUES ($1) ON CONFLICT DO NOTHING")
+        .bind(tag)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO quote_tags (quote_id, tag) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(id)
+        .bind(tag)
+        .execute(&mut *tx)
+        .await?;
+    let tags = tags_of(&mut *tx, id).await?;
+    tx.commit().await?;
+    Ok(tags)
+}
+
+#[get("tags")]
+async fn list_tags(pool: SharedDBPool) -> Result<HttpResponse, QuoteBookError> {
+    let tags = sqlx::query_as::<_, Tag>(
+        "SELECT t.name, COUNT(q.id) AS quotes FROM tags t \
+         LEFT JOIN quote_tags qt ON qt.tag = t.name \
+         LEFT JOIN quotes q ON q.id = qt.quote_id AND q.deleted_at IS NULL \
+         GROUP BY t.name ORDER BY t.name",
+    )
+    .fetch_all(&**pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[get("tags/{id}")]
+async fn quote_tags(id: Path<String>, pool: SharedDBPool) -> Result<HttpResponse, QuoteBookError> {
+    let id = parse_id(&id)?;
+    check_quote(&**pool, &id).await?;
+
+    Ok(HttpResponse::Ok().json(tags_of(&**pool, &id).await?))
+}
+
+#[put("tags/{id}/{tag}")]
+async fn tag_quote(
+    params: Path<(String, String)>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let (id, tag) = params.into_inner();
+    let id = parse_id(&id)?;
+    let tag = clean_tag(&tag).map_err(|error| QuoteBookError::Invalid(vec![error]))?;
+
+    Ok(HttpResponse::Ok().json(add_tag(&pool, &id, &tag).await?))
+}
+
+#[delete("tags/{id}/{tag}")]
+async fn untag_quote(
+    params: Path<(String, String)>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let (id, tag) = params.into_inner();
+    let id = parse_id(&id)?;
+    // A tag that could never have been added can't be on the quote either
+    let tag = clean_tag(&tag).map_err(|_| QuoteBookError::NotFound)?;
+
+    let done = sqlx::query("DELETE FROM quote_tags WHERE quote_id = $1 AND tag = $2")
+        .bind(id)
+        .bind(&tag)
+        .execute(&**pool)
+        .await?;
+    if done.rows_affected() == 0 {
+        return Err(QuoteBookError::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().json(tags_of(&**pool, &id).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_cleaned_up_and_lowercased() {
+        assert_eq!(
+            Ok("christmas eve".to_string()),
+            clean_tag("  Christmas Eve ")
+        );
+        assert_eq!("tag", clean_tag(" ").unwrap_err().field);
+        assert!(clean_tag(&"x".repeat(MAX_TAG_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! Moving the whole quote book in and out in bulk, as JSON Lines, CSV or
+//! YAML
+
+use actix_web::http::header::{self, Header as _};
+use actix_web::web::{Bytes, Query};
+use actix_web::{error, get, post, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+use super::{
+    authorize, Action, Quote, QuoteBookError, QuoteRequest, Role, SharedDBPool, SharedTokenKey,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataFormat {
+    JsonLines,
+    Csv,
+    Yaml,
+}
+
+impl DataFormat {
+    fn from_mime(essence: &str) -> Option<Self> {
+        match essence {
+            "application/x-ndjson" | "application/jsonl" => Some(Self::JsonLines),
+            "text/csv" => Some(Self::Csv),
+            "application/yaml" | "text/yaml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            Self::JsonLines => "application/x-ndjson",
+            Self::Csv => "text/csv",
+            Self::Yaml => "application/yaml",
+        }
+    }
+
+    /// Write out a single quote. YAML quotes are written as a list of one,
+    /// so that one after the other they make up a list of them all.
+    fn encode(self, record: &QuoteRecord, first: bool) -> Result<Vec<u8>, String> {
+        match self {
+            Self::JsonLines => {
+                let mut line = serde_json::to_vec(record).map_err(|err| err.to_string())?;
+                line.push(b'\n');
+                Ok(line)
+            }
+            Self::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(first)
+                    .from_writer(Vec::new());
+                writer.serialize(record).map_err(|err| err.to_string())?;
+                writer.into_inner().map_err(|err| err.to_string())
+            }
+            Self::Yaml => serde_yml::to_string(&[record])
+                .map(String::into_bytes)
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Read every quote, or what went wrong with each row that couldn't be
+    /// read. Rows count from 1, not including any header. JSON Lines rows are
+    /// the lines they're on, counting blank ones, so they can be found in the
+    /// file.
+    fn decode(self, body: &[u8]) -> Result<Vec<QuoteRecord>, Vec<RowError>> {
+        let rows: Vec<(usize, Result<QuoteRecord, String>)> = match self {
+            // A line that isn't UTF-8 is a bad row, rather than a row with
+            // some of its text replaced
+            Self::JsonLines => body
+                .split(|&byte| byte == b'\n')
+                .enumerate()
+                .filter(|(_, line)| !line.trim_ascii().is_empty())
+                .map(|(i, line)| {
+                    let row = std::str::from_utf8(line)
+                        .map_err(|err| format!("not valid UTF-8: {err}"))
+                        .and_then(|line| serde_json::from_str(line).map_err(|err| err.to_string()));
+                    (i, row)
+                })
+                .collect(),
+            Self::Csv => csv::Reader::from_reader(body)
+                .deserialize()
+                .map(|row| row.map_err(|err| err.to_string()))
+                .enumerate()
+                .collect(),
+            Self::Yaml => match serde_yml::from_slice::<Vec<serde_yml::Value>>(body) {
+                Ok(values) => values
+                    .into_iter()
+                    .map(|value| serde_yml::from_value(value).map_err(|err| err.to_string()))
+                    .enumerate()
+                    .collect(),
+                Err(err) => vec![(0, Err(err.to_string()))],
+            },
+        };
+
+        let mut records = Vec::new();
+        let mut errors = Vec::new();
+        for (i, row) in rows {
+            match row.and_then(QuoteRecord::validate) {
+                Ok(record) => records.push(record),
+                Err(message) => errors.push(RowError {
+                    row: i + 1,
+                    message,
+                }),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(records)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// A quote as it's exported and imported. Only the author and the quote
+/// itself are needed to import one.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+struct QuoteRecord {
+    id: Option<Uuid>,
+    author: String,
+    #[allow(clippy::struct_field_names)]
+    quote: String,
+    created_at: Option<DateTime<Utc>>,
+    version: Option<i32>,
+}
+
+impl QuoteRecord {
+    /// Clean up the quote the same way drafts are
+    fn validate(self) -> Result<Self, String> {
+        let request = QuoteRequest {
+            author: self.author,
+            quote: self.quote,
+        };
+        match request.validate() {
+            Ok(QuoteRequest { author, quote }) => Ok(Self {
+                author,
+                quote,
+                ..self
+            }),
+            Err(errors) => Err(errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")),
+        }
+    }
+
+    /// Add the quote to the book, or with `upsert`, replace the quote with
+    /// the same ID if there is one
+    async fn save(&self, conn: &mut PgConnection, upsert: bool) -> Result<Quote, sqlx::Error> {
+        let on_conflict = if upsert {
+            "ON CONFLICT (id) DO UPDATE SET author = EXCLUDED.author, quote = EXCLUDED.quote, \
+             version = quotes.version + 1, deleted_at = NULL"
+        } else {
+            ""
+        };
+        let sql = format!(
+            "INSERT INTO quotes (id, author, quote, created_at, version) \
+             VALUES ($1, $2, $3, COALESCE($4, CURRENT_TIMESTAMP), COALESCE($5, 1)) \
+             {on_conflict} RETURNING *"
+        );
+        sqlx::query_as::<_, Quote>(&sql)
+            .bind(self.id.unwrap_or_else(Uuid::new_v4))
+            .bind(&self.author)
+            .bind(&self.quote)
+            .bind(self.created_at)
+            .bind(self.version)
+            .fetch_one(conn)
+            .await
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct RowError {
+    row: usize,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Imported {
+    imported: usize,
+}
+
+#[get("/export")]
+async fn export(pool: SharedDBPool, request: HttpRequest) -> HttpResponse {
+    let format = header::Accept::parse(&request)
+        .ok()
+        .and_then(|accept| DataFormat::from_mime(accept.preference().essence_str()))
+        .unwrap_or(DataFormat::JsonLines);
+
+    // Send quotes on as they come out of the database, rather than holding
+    // them all in memory
+    let (tx, rx) = mpsc::channel::<Result<Bytes, String>>(16);
+    tokio::spawn(async move {
+        let mut rows = sqlx::query_as::<_, QuoteRecord>(
+            "SELECT id, author, quote, created_at, version FROM quotes \
+             WHERE deleted_at IS NULL ORDER BY created_at, id",
+        )
+        .fetch(&**pool);
+
+        let mut first = true;
+        while let Some(row) = rows.next().await {
+            let chunk = row
+                .map_err(|err| err.to_string())
+                .and_then(|record| format.encode(&record, first))
+                .map(Bytes::from);
+            let failed = chunk.is_err();
+            if let Err(err) = &chunk {
+                tracing::error!(error = %err, "quote export failed");
+            }
+            // Stop if the client has gone away, or there's nothing more to send
+            if tx.send(chunk).await.is_err() || failed {
+                return;
+            }
+            first = false;
+        }
+
+        // Nothing at all isn't a list in YAML
+        if first && format == DataFormat::Yaml {
+            let _ = tx.send(Ok(Bytes::from_static(b"[]\n"))).await;
+        }
+    });
+
+    HttpResponse::Ok().content_type(format.mime()).streaming(
+        ReceiverStream::new(rx).map(|chunk| chunk.map_err(error::ErrorInternalServerError)),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportParams {
+    /// Replace quotes that already exist, rather than failing
+    #[serde(default)]
+    upsert: bool,
+}
+
+/// Import every quote or none of them
+#[post("/import")]
+async fn import(
+    body: Bytes,
+    params: Query<ImportParams>,
+    pool: SharedDBPool,
+    key: SharedTokenKey,
+    request: HttpRequest,
+) -> Result<HttpResponse, QuoteBookError> {
+    authorize(&request, &key, Role::Editor)?;
+    let format = match request.mime_type() {
+        Ok(Some(mime)) => {
+            DataFormat::from_mime(mime.essence_str()).ok_or(QuoteBookError::UnsupportedMediaType)?
+        }
+        Ok(None) => DataFormat::JsonLines,
+        Err(_) => return Err(QuoteBookError::BadRequest("the content type can't be read")),
+    };
+
+    let records = format.decode(&body).map_err(QuoteBookError::InvalidRows)?;
+
+    let mut tx = pool.begin().await?;
+    for (i, record) in records.iter().enumerate() {
+        let saved = match record.save(&mut tx, params.upsert).await {
+            Ok(quote) => quote.record(&mut tx, Action::Import).await,
+            Err(err) => Err(err),
+        };
+        match saved {
+            Ok(()) => (),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                return Err(QuoteBookError::InvalidRows(vec![RowError {
+                    row: i + 1,
+                    message: "a quote with this id already exists".to_string(),
+                }]));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(Imported {
+        imported: records.len(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(author: &str) -> QuoteRecord {
+        QuoteRecord {
+            id: Some(Uuid::nil()),
+            author: author.to_string(),
+            quote: "Ho ho ho".to_string(),
+            created_at: Some(DateTime::UNIX_EPOCH),
+            version: Some(1),
+        }
+    }
+
+    #[test]
+    fn exported_quotes_can_be_imported_again() {
+        for format in [DataFormat::JsonLines, DataFormat::Csv, DataFormat::Yaml] {
+            let mut body = format.encode(&record("Santa"), true).unwrap();
+            body.extend(format.encode(&record("Rudolph"), false).unwrap());
+
+            let records = format.decode(&body).unwrap();
+            let authors: Vec<_> = records.iter().map(|r| r.author.as_str()).collect();
+            assert_eq!(vec!["Santa", "Rudolph"], authors, "{format:?}");
+            assert_eq!(Some(Uuid::nil()), records[0].id);
+        }
+    }
+
+    #[test]
+    fn every_bad_row_is_reported() {
+        let body = "author,quote\nSanta,Ho ho ho\n,Nobody said this\nRudolph\n";
+        let errors = DataFormat::Csv.decode(body.as_bytes()).unwrap_err();
+
+        let rows: Vec<_> = errors.iter().map(|e| e.row).collect();
+        assert_eq!(vec![2, 3], rows);
+    }
+
+    #[test]
+    fn lines_that_arent_utf8_are_bad_rows() {
+        let mut body = DataFormat::JsonLines
+            .encode(&record("Santa"), true)
+            .unwrap();
+        // Blank lines still count, so rows are the lines they're on
+        body.extend(b"\r\n{\"author\": \"R\xffdolph\", \"quote\": \"Ho ho ho\"}\r\n");
+        body.extend(DataFormat::JsonLines.encode(&record("Elf"), false).unwrap());
+
+        let errors = DataFormat::JsonLines.decode(&body).unwrap_err();
+        assert_eq!(1, errors.len());
+        assert_eq!(3, errors[0].row);
+        assert!(errors[0].message.contains("UTF-8"));
+    }
+}